@@ -0,0 +1,205 @@
+use crate::error::Failure;
+use crate::options::RequestOptions;
+use crate::upload::{self, Feeder};
+use crate::{RbHttpClient, RbHttpResponse, ResponseData, gvl, parse_method};
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
+use magnus::scan_args::{get_kwargs, scan_args};
+use magnus::{Error as MagnusError, IntoValue, RArray, RHash, Ruby, Value, exception};
+use rquest::{Method, RequestBuilder};
+
+const DEFAULT_CONCURRENCY: usize = 32;
+
+/// A batch entry, parsed from `[verb, url, options]` while holding the GVL.
+struct BatchRequest {
+    method: Method,
+    url: String,
+    options: RequestOptions,
+}
+
+/// Where an entry was sent, for reporting its outcome.
+struct Target {
+    method: Method,
+    url: String,
+}
+
+/// A prepared entry, with the client settings its options produced and the
+/// feeders for its streamed body.
+type Sendable = (RbHttpClient, RequestBuilder, Vec<Feeder>);
+
+/// Prepares every entry on `client`.
+fn prepare_all(
+    client: &RbHttpClient,
+    requests: Vec<BatchRequest>,
+) -> Result<(Vec<Target>, Vec<Sendable>), MagnusError> {
+    let mut targets = Vec::with_capacity(requests.len());
+    let mut prepared = Vec::with_capacity(requests.len());
+
+    for request in requests {
+        let request = client.prepare_request(request.method, request.url, request.options)?;
+        targets.push(Target {
+            method: request.method,
+            url: request.url,
+        });
+        prepared.push((request.client, request.req, request.feeders));
+    }
+
+    Ok((targets, prepared))
+}
+
+/// Sends one entry while feeding its streamed body. A Ruby error raised while
+/// reading the body only fails this entry.
+async fn send((client, req, feeders): Sendable) -> Result<ResponseData, Failure> {
+    match upload::feeding(client.perform(req), feeders).await {
+        Ok(outcome) => outcome,
+        Err(error) => Err(Failure::Ruby(error)),
+    }
+}
+
+/// Converts the outcome of one entry into a `Response` or error object.
+fn result_value(
+    target: &Target,
+    outcome: Result<ResponseData, Failure>,
+) -> Result<Value, MagnusError> {
+    match outcome {
+        Ok(data) => Ok(RbHttpResponse::new(data).into_value()),
+        Err(failure) => error_object(failure.into_error(target.method.as_str(), &target.url)),
+    }
+}
+
+/// Parses `(requests, concurrency: n)`.
+fn parse_args(args: &[Value]) -> Result<(Vec<BatchRequest>, usize), MagnusError> {
+    let args = scan_args::<(RArray,), (Option<RHash>,), (), (), (), ()>(args)?;
+    let (requests,) = args.required;
+    let (options,) = args.optional;
+
+    let concurrency = match options {
+        Some(options) => {
+            let kwargs = get_kwargs::<_, (), (Option<usize>,), ()>(options, &[], &["concurrency"])?;
+            kwargs.optional.0.unwrap_or(DEFAULT_CONCURRENCY)
+        }
+        None => DEFAULT_CONCURRENCY,
+    };
+    if concurrency == 0 {
+        return Err(MagnusError::new(
+            exception::arg_error(),
+            "concurrency must be at least 1",
+        ));
+    }
+
+    let requests = requests
+        .to_vec::<RArray>()?
+        .into_iter()
+        .map(parse_entry)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok((requests, concurrency))
+}
+
+fn parse_entry(entry: RArray) -> Result<BatchRequest, MagnusError> {
+    let values = entry.to_vec::<Value>()?;
+    if !(2..=3).contains(&values.len()) {
+        return Err(MagnusError::new(
+            exception::arg_error(),
+            format!(
+                "expected [verb, url] or [verb, url, options], got {} elements",
+                values.len()
+            ),
+        ));
+    }
+
+    let method = parse_method(values[0])?;
+    let (url, options) = RequestOptions::parse(&values[1..])?;
+    Ok(BatchRequest {
+        method,
+        url,
+        options,
+    })
+}
+
+/// Runs a batch of requests on the runtime with at most `concurrency` in
+/// flight, releasing the GVL for the whole batch.
+///
+/// Returns one entry per request, in input order: a `Response`, or the
+/// `Rquest::HTTP::Error` that request failed with. An exception raised while
+/// reading a streamed body takes the place of that request's response too.
+pub(crate) fn parallel(client: &RbHttpClient, args: &[Value]) -> Result<RArray, MagnusError> {
+    let (requests, concurrency) = parse_args(args)?;
+
+    let (targets, prepared) = prepare_all(client, requests)?;
+
+    // Requests complete in any order, so that a slow one doesn't hold back the
+    // rest, and are put back in input order afterwards.
+    let mut outcomes: Vec<Option<Result<ResponseData, Failure>>> =
+        (0..prepared.len()).map(|_| None).collect();
+    gvl::block_on(
+        stream::iter(
+            prepared
+                .into_iter()
+                .enumerate()
+                .map(|(index, sendable)| async move { (index, send(sendable).await) }),
+        )
+        .buffer_unordered(concurrency)
+        .for_each(|(index, outcome)| {
+            outcomes[index] = Some(outcome);
+            future::ready(())
+        }),
+    )?;
+
+    let results = RArray::with_capacity(outcomes.len());
+    for (target, outcome) in targets.iter().zip(outcomes) {
+        let outcome = outcome.expect("every request in the batch completes");
+        results.push(result_value(target, outcome)?)?;
+    }
+
+    Ok(results)
+}
+
+/// Like [`parallel`], but yields `(index, response_or_error)` to the block as
+/// each request completes.
+///
+/// The block runs on the calling thread, which only holds the GVL while it
+/// runs. Leaving the block early (`break`, `return` or an exception) drops the
+/// remaining requests, cancelling them.
+pub(crate) fn each_completed(client: &RbHttpClient, args: &[Value]) -> Result<(), MagnusError> {
+    let ruby = Ruby::get().expect("batches are run from a Ruby thread");
+    if !ruby.block_given() {
+        return Err(MagnusError::new(
+            exception::local_jump_error(),
+            "no block given (yield)",
+        ));
+    }
+
+    let (requests, concurrency) = parse_args(args)?;
+    let (targets, prepared) = prepare_all(client, requests)?;
+
+    let batch = async {
+        let mut completed = stream::iter(
+            prepared
+                .into_iter()
+                .enumerate()
+                .map(|(index, sendable)| async move { (index, send(sendable).await) }),
+        )
+        .buffer_unordered(concurrency);
+
+        while let Some((index, outcome)) = completed.next().await {
+            gvl::with_gvl(|| {
+                let result = result_value(&targets[index], outcome)?;
+                ruby.yield_values::<_, Value>((index, result))
+            })?;
+        }
+
+        Ok::<_, MagnusError>(())
+    };
+
+    gvl::block_on(batch)?
+}
+
+/// Unwraps the exception object built for a failed entry, so it can be
+/// returned instead of raised.
+fn error_object(error: MagnusError) -> Result<Value, MagnusError> {
+    match error.value() {
+        Some(exception) => Ok(exception),
+        None => Err(error),
+    }
+}
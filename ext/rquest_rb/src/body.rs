@@ -0,0 +1,209 @@
+use crate::error::Failure;
+use crate::{Timeouts, charset, error, gvl};
+use bytes::{Bytes, BytesMut};
+use magnus::scan_args::scan_args;
+use magnus::{Error as MagnusError, Module, RClass, RString, Ruby, Value, exception, method};
+use rquest::{Method, Response as RquestResponse};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+/// A response body that is read from the connection on demand.
+pub(crate) struct BodyStream {
+    state: Mutex<StreamState>,
+    method: Method,
+    url: String,
+    content_type: Option<String>,
+    timeouts: Timeouts,
+    started: Instant,
+}
+
+struct StreamState {
+    /// The response being read, until it reaches the end or fails.
+    response: Option<RquestResponse>,
+    /// What's left of a chunk larger than the last `readpartial` asked for.
+    pending: Bytes,
+    /// Whether any chunk has been handed out by `readpartial` or `each`.
+    streamed: bool,
+    /// The whole body, once `to_s` has read it.
+    buffered: Option<Bytes>,
+}
+
+impl BodyStream {
+    pub(crate) fn new(
+        response: RquestResponse,
+        method: Method,
+        url: String,
+        timeouts: Timeouts,
+        started: Instant,
+    ) -> Self {
+        let content_type = response
+            .headers()
+            .get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+
+        Self {
+            state: Mutex::new(StreamState {
+                response: Some(response),
+                pending: Bytes::new(),
+                streamed: false,
+                buffered: None,
+            }),
+            method,
+            url,
+            content_type,
+            timeouts,
+            started,
+        }
+    }
+
+    /// Locks the stream for reading. Fails rather than blocks if another
+    /// thread is reading it, since that thread may be waiting for the GVL.
+    fn lock(&self) -> Result<MutexGuard<'_, StreamState>, MagnusError> {
+        self.state.try_lock().map_err(|_| {
+            MagnusError::new(
+                exception::io_error(),
+                "body is being read by another thread",
+            )
+        })
+    }
+
+    /// Reads the next non-empty chunk from the connection, or `None` at the end.
+    fn read_chunk(&self, state: &mut StreamState) -> Result<Option<Bytes>, MagnusError> {
+        if !state.pending.is_empty() {
+            return Ok(Some(std::mem::take(&mut state.pending)));
+        }
+
+        while let Some(response) = state.response.as_mut() {
+            match gvl::block_on(response.chunk())? {
+                Ok(Some(chunk)) if chunk.is_empty() => continue,
+                Ok(Some(chunk)) => return Ok(Some(chunk)),
+                Ok(None) => state.response = None,
+                Err(e) => {
+                    state.response = None;
+                    let kind = self
+                        .timeouts
+                        .refine(error::classify(&e), self.started.elapsed());
+                    return Err(
+                        Failure::Rquest(kind, e).into_error(self.method.as_str(), &self.url)
+                    );
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Takes the next chunk for `readpartial` or `each`, which can't be mixed
+    /// with reading the whole body.
+    fn next_chunk(&self, max: Option<usize>) -> Result<Option<Bytes>, MagnusError> {
+        let mut state = self.lock()?;
+        if state.buffered.is_some() {
+            return Err(already_read());
+        }
+        state.streamed = true;
+
+        let Some(mut chunk) = self.read_chunk(&mut state)? else {
+            return Ok(None);
+        };
+        if let Some(max) = max.filter(|&max| max < chunk.len()) {
+            state.pending = chunk.split_off(max);
+        }
+        Ok(Some(chunk))
+    }
+
+    /// Reads the rest of the body into memory. The result is kept, so later
+    /// calls return the same bytes.
+    pub(crate) fn read_to_end(&self) -> Result<Bytes, MagnusError> {
+        let mut state = self.lock()?;
+        if let Some(buffered) = &state.buffered {
+            return Ok(buffered.clone());
+        }
+        if state.streamed {
+            return Err(already_read());
+        }
+
+        let mut body = BytesMut::new();
+        while let Some(chunk) = self.read_chunk(&mut state)? {
+            body.extend_from_slice(&chunk);
+        }
+
+        let body = body.freeze();
+        state.buffered = Some(body.clone());
+        Ok(body)
+    }
+
+    pub(crate) fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+}
+
+fn already_read() -> MagnusError {
+    MagnusError::new(
+        exception::io_error(),
+        "body can't be both streamed and read as a whole",
+    )
+}
+
+/// `Rquest::HTTP::Response::Body`, returned by `Response#body` for streamed
+/// responses.
+#[magnus::wrap(class = "Rquest::HTTP::Response::Body")]
+pub(crate) struct RbBody {
+    stream: Arc<BodyStream>,
+}
+
+impl RbBody {
+    pub(crate) fn new(stream: Arc<BodyStream>) -> Self {
+        Self { stream }
+    }
+
+    /// Returns the next chunk of at most `size` bytes (a whole chunk when
+    /// omitted), raising `EOFError` at the end of the body.
+    fn readpartial(&self, args: &[Value]) -> Result<RString, MagnusError> {
+        let args = scan_args::<(), (Option<usize>,), (), (), (), ()>(args)?;
+        let (size,) = args.optional;
+        if size == Some(0) {
+            return Ok(RString::buf_new(0));
+        }
+
+        match self.stream.next_chunk(size)? {
+            Some(chunk) => Ok(RString::from_slice(&chunk)),
+            None => Err(MagnusError::new(
+                exception::eof_error(),
+                "end of file reached",
+            )),
+        }
+    }
+
+    /// Yields each chunk as it arrives. The GVL is only held while the block
+    /// runs.
+    fn each(&self) -> Result<(), MagnusError> {
+        let ruby = Ruby::get().expect("bodies are read from a Ruby thread");
+        if !ruby.block_given() {
+            return Err(MagnusError::new(
+                exception::local_jump_error(),
+                "no block given (yield)",
+            ));
+        }
+
+        while let Some(chunk) = self.stream.next_chunk(None)? {
+            ruby.yield_value::<_, Value>(RString::from_slice(&chunk))?;
+        }
+        Ok(())
+    }
+
+    /// Reads the whole body, tagged with its detected encoding.
+    fn to_s(&self) -> Result<RString, MagnusError> {
+        let body = self.stream.read_to_end()?;
+        let encoding = charset::encoding(self.stream.content_type(), &body);
+        Ok(RString::enc_new(&body, encoding))
+    }
+}
+
+pub(crate) fn define_body(ruby: &Ruby, response_class: RClass) -> Result<(), MagnusError> {
+    let body_class = response_class.define_class("Body", ruby.class_object())?;
+    body_class.define_method("readpartial", method!(RbBody::readpartial, -1))?;
+    body_class.define_method("each", method!(RbBody::each, 0))?;
+    body_class.define_method("to_s", method!(RbBody::to_s, 0))?;
+    Ok(())
+}
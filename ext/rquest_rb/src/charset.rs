@@ -0,0 +1,106 @@
+use magnus::encoding::RbEncoding;
+
+/// How many bytes of an HTML document are searched for a `<meta>` charset,
+/// as in the HTML encoding sniffing algorithm.
+const META_SNIFF_LIMIT: usize = 1024;
+
+/// Extracts the `charset` parameter from a `Content-Type` header value.
+pub(crate) fn from_content_type(content_type: &str) -> Option<String> {
+    let charset_part = content_type
+        .split(';')
+        .skip(1)
+        .find(|part| part.trim().to_lowercase().starts_with("charset="))?;
+
+    let charset = charset_part
+        .trim()
+        .split('=')
+        .nth(1)
+        .unwrap_or("")
+        .trim()
+        .trim_matches(|c| c == '"' || c == '\'');
+
+    if charset.is_empty() {
+        None
+    } else {
+        Some(charset.to_string())
+    }
+}
+
+/// Detects UTF-8 and UTF-16 from a byte order mark.
+fn from_bom(body: &[u8]) -> Option<&'static str> {
+    if body.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        Some("UTF-8")
+    } else if body.starts_with(&[0xFF, 0xFE]) {
+        Some("UTF-16LE")
+    } else if body.starts_with(&[0xFE, 0xFF]) {
+        Some("UTF-16BE")
+    } else {
+        None
+    }
+}
+
+/// Finds `<meta charset="...">` or `<meta http-equiv="Content-Type"
+/// content="...; charset=...">` near the start of an HTML document.
+fn from_meta(body: &[u8]) -> Option<String> {
+    let head = &body[..body.len().min(META_SNIFF_LIMIT)];
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+
+    head.match_indices("<meta").find_map(|(start, _)| {
+        let tag = &head[start..];
+        let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+        let value = &tag[tag.find("charset=")? + "charset=".len()..];
+        let value = value.trim_start_matches(['"', '\'']);
+        let end = value
+            .find(|c: char| matches!(c, '"' | '\'' | ';' | '/' | '>') || c.is_whitespace())
+            .unwrap_or(value.len());
+
+        Some(&value[..end])
+            .filter(|charset| !charset.is_empty())
+            .map(str::to_string)
+    })
+}
+
+/// The lowercased MIME type of a `Content-Type`, without its parameters.
+pub(crate) fn mime_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Whether a body of this type is text, and so UTF-8 unless stated otherwise.
+pub(crate) fn is_text(content_type: Option<&str>) -> bool {
+    content_type.map(mime_type).is_some_and(|mime| {
+        mime.starts_with("text/")
+            || mime.ends_with("json")
+            || mime.ends_with("xml")
+            || mime.ends_with("javascript")
+    })
+}
+
+/// Detects a body's charset from, in order: the `Content-Type` header, a byte
+/// order mark, and for HTML, a `<meta>` declaration.
+pub(crate) fn detect(content_type: Option<&str>, body: &[u8]) -> Option<String> {
+    let sniff_meta = content_type.is_none_or(|content_type| mime_type(content_type) == "text/html");
+
+    content_type
+        .and_then(from_content_type)
+        .or_else(|| from_bom(body).map(str::to_string))
+        .or_else(|| sniff_meta.then(|| from_meta(body)).flatten())
+}
+
+/// The Ruby encoding for a body: its detected charset, or UTF-8 for text and
+/// ASCII-8BIT for everything else when none is declared.
+pub(crate) fn encoding(content_type: Option<&str>, body: &[u8]) -> RbEncoding {
+    detect(content_type, body)
+        .and_then(|charset| RbEncoding::find(&charset))
+        .unwrap_or_else(|| {
+            if is_text(content_type) {
+                RbEncoding::utf8()
+            } else {
+                RbEncoding::ascii8bit()
+            }
+        })
+}
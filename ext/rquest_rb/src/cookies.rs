@@ -0,0 +1,501 @@
+use bytes::Bytes;
+use cookie_store::{Cookie, CookieDomain, CookieExpiration, CookieStore as Store, RawCookie};
+use magnus::scan_args::{get_kwargs, scan_args};
+use magnus::{
+    Error as MagnusError, Module, Object, RHash, RModule, Ruby, Symbol, TryConvert, Value,
+    exception, function, method,
+};
+use rquest::cookie::CookieStore;
+use rquest::header::HeaderValue;
+use serde_json::{Value as JsonValue, json};
+use std::convert::Infallible;
+use std::fmt::Write as _;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+const NETSCAPE_HEADER: &str = "# Netscape HTTP Cookie File";
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+/// Cookies kept across requests. As rquest's `CookieStore`, it is given to
+/// the rquest client, which stores `Set-Cookie` headers and sends matching
+/// cookies on every request, redirects included.
+#[derive(Default)]
+pub(crate) struct CookieJar(RwLock<Store>);
+
+impl CookieJar {
+    fn read(&self) -> RwLockReadGuard<'_, Store> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Store> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        let cookies = cookie_headers
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| RawCookie::parse(value.to_string()).ok());
+        self.write().store_response_cookies(cookies, url);
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let header = self
+            .read()
+            .get_request_values(url)
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        if header.is_empty() {
+            None
+        } else {
+            HeaderValue::from_maybe_shared(Bytes::from(header)).ok()
+        }
+    }
+}
+
+/// A cookie file format for `CookieJar#save` and `CookieJar.load`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CookieFormat {
+    /// The `cookies.txt` format read by curl (`-b`) and wget.
+    Netscape,
+    /// Playwright's storage state: `{ "cookies": [...], "origins": [] }`.
+    Json,
+}
+
+impl CookieFormat {
+    fn from_value(value: Value) -> Result<Self, MagnusError> {
+        let name = match Symbol::from_value(value) {
+            Some(symbol) => symbol.name()?.into_owned(),
+            None => String::try_convert(value)?,
+        };
+
+        match name.as_str() {
+            "netscape" => Ok(CookieFormat::Netscape),
+            "json" => Ok(CookieFormat::Json),
+            _ => Err(MagnusError::new(
+                exception::arg_error(),
+                format!(
+                    "unknown cookie format: {} (expected :netscape or :json)",
+                    name
+                ),
+            )),
+        }
+    }
+
+    /// Playwright's files are JSON objects, while Netscape files are lines of
+    /// tab-separated fields.
+    fn detect(contents: &str) -> Self {
+        match contents.trim_start().chars().next() {
+            Some('{' | '[') => CookieFormat::Json,
+            _ => CookieFormat::Netscape,
+        }
+    }
+}
+
+/// A cookie with every attribute the file formats carry.
+#[derive(Debug, Clone, PartialEq)]
+struct SavedCookie {
+    name: String,
+    value: String,
+    /// The domain, without a leading dot.
+    domain: String,
+    /// Whether the cookie is also sent to subdomains, i.e. it was set with a
+    /// `Domain` attribute rather than host-only.
+    include_subdomains: bool,
+    path: String,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<String>,
+    /// Unix time in seconds, or `None` for a session cookie.
+    expires: Option<i64>,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+impl SavedCookie {
+    fn from_cookie(cookie: &Cookie<'static>) -> Option<Self> {
+        let (domain, include_subdomains) = match &cookie.domain {
+            CookieDomain::HostOnly(domain) => (domain.clone(), false),
+            CookieDomain::Suffix(domain) => (domain.clone(), true),
+            CookieDomain::NotPresent | CookieDomain::Empty => return None,
+        };
+        let expires = match &cookie.expires {
+            CookieExpiration::AtUtc(at) => Some(at.unix_timestamp()),
+            CookieExpiration::SessionEnd => None,
+        };
+
+        Some(Self {
+            name: cookie.name().to_string(),
+            value: cookie.value().to_string(),
+            domain,
+            include_subdomains,
+            path: String::from(cookie.path.clone()),
+            secure: cookie.secure().unwrap_or(false),
+            http_only: cookie.http_only().unwrap_or(false),
+            same_site: cookie.same_site().map(|same_site| same_site.to_string()),
+            expires,
+        })
+    }
+
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// The cookie as a `Set-Cookie` header from [`Self::url`], so the store
+    /// applies the same rules as to cookies received from a server.
+    fn set_cookie(&self, now: i64) -> String {
+        let mut header = format!("{}={}; Path={}", self.name, self.value, self.path);
+        if self.include_subdomains {
+            let _ = write!(header, "; Domain={}", self.domain);
+        }
+        if let Some(expires) = self.expires {
+            let _ = write!(header, "; Max-Age={}", expires - now);
+        }
+        if self.secure {
+            header.push_str("; Secure");
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        if let Some(same_site) = &self.same_site {
+            let _ = write!(header, "; SameSite={}", same_site);
+        }
+        header
+    }
+
+    fn url(&self) -> Result<Url, String> {
+        Url::parse(&format!("https://{}{}", self.domain, self.path))
+            .map_err(|e| format!("invalid domain {:?}: {}", self.domain, e))
+    }
+
+    /// A line of a Netscape cookie file. HttpOnly cookies use curl's
+    /// `#HttpOnly_` prefix; SameSite can't be written.
+    fn to_netscape(&self) -> String {
+        let bool_field = |value: bool| if value { "TRUE" } else { "FALSE" };
+        format!(
+            "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            if self.http_only { HTTP_ONLY_PREFIX } else { "" },
+            if self.include_subdomains { "." } else { "" },
+            self.domain,
+            bool_field(self.include_subdomains),
+            self.path,
+            bool_field(self.secure),
+            self.expires.unwrap_or(0),
+            self.name,
+            self.value,
+        )
+    }
+
+    /// Parses a line of a Netscape cookie file, or returns `None` for blank
+    /// lines and comments.
+    fn from_netscape(line: &str) -> Option<Result<Self, String>> {
+        let (http_only, line) = match line.strip_prefix(HTTP_ONLY_PREFIX) {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        if line.trim().is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let fields: Vec<&str> = line.trim_end_matches(['\r', '\n']).split('\t').collect();
+        let [
+            domain,
+            include_subdomains,
+            path,
+            secure,
+            expires,
+            name,
+            value,
+        ] = fields.as_slice()
+        else {
+            return Some(Err(format!(
+                "expected 7 tab-separated fields, got {}",
+                fields.len()
+            )));
+        };
+        let expires = match expires.parse::<i64>() {
+            Ok(0) => None,
+            Ok(expires) => Some(expires),
+            Err(_) => return Some(Err(format!("invalid expiry: {:?}", expires))),
+        };
+
+        Some(Ok(Self {
+            name: name.to_string(),
+            value: value.to_string(),
+            domain: domain.trim_start_matches('.').to_string(),
+            include_subdomains: include_subdomains.eq_ignore_ascii_case("TRUE"),
+            path: path.to_string(),
+            secure: secure.eq_ignore_ascii_case("TRUE"),
+            http_only,
+            same_site: None,
+            expires,
+        }))
+    }
+
+    /// A cookie of a Playwright storage state. Domain cookies are written with
+    /// a leading dot, and session cookies expire at `-1`.
+    fn to_json(&self) -> JsonValue {
+        let mut cookie = json!({
+            "name": self.name,
+            "value": self.value,
+            "domain": if self.include_subdomains {
+                format!(".{}", self.domain)
+            } else {
+                self.domain.clone()
+            },
+            "path": self.path,
+            "expires": self.expires.unwrap_or(-1),
+            "httpOnly": self.http_only,
+            "secure": self.secure,
+        });
+        if let Some(same_site) = &self.same_site {
+            cookie["sameSite"] = JsonValue::from(same_site.as_str());
+        }
+        cookie
+    }
+
+    fn from_json(cookie: &JsonValue) -> Result<Self, String> {
+        let string = |key: &str| {
+            cookie
+                .get(key)
+                .and_then(JsonValue::as_str)
+                .map(str::to_string)
+        };
+        let flag = |key: &str| {
+            cookie
+                .get(key)
+                .and_then(JsonValue::as_bool)
+                .unwrap_or(false)
+        };
+
+        let name = string("name").ok_or("cookie has no name")?;
+        let domain = string("domain").ok_or_else(|| format!("cookie {} has no domain", name))?;
+        let expires = match cookie.get("expires").and_then(JsonValue::as_f64) {
+            Some(expires) if expires >= 0.0 => Some(expires as i64),
+            _ => None,
+        };
+
+        Ok(Self {
+            value: string("value").unwrap_or_default(),
+            include_subdomains: domain.starts_with('.'),
+            domain: domain.trim_start_matches('.').to_string(),
+            path: string("path").unwrap_or_else(|| "/".to_string()),
+            secure: flag("secure"),
+            http_only: flag("httpOnly"),
+            same_site: string("sameSite"),
+            expires,
+            name,
+        })
+    }
+}
+
+fn write_netscape(cookies: &[SavedCookie]) -> String {
+    let mut contents = format!("{}\n\n", NETSCAPE_HEADER);
+    for cookie in cookies {
+        contents.push_str(&cookie.to_netscape());
+        contents.push('\n');
+    }
+    contents
+}
+
+fn read_netscape(contents: &str) -> Result<Vec<SavedCookie>, String> {
+    contents
+        .lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            SavedCookie::from_netscape(line)
+                .map(|cookie| cookie.map_err(|e| format!("line {}: {}", index + 1, e)))
+        })
+        .collect()
+}
+
+fn write_json(cookies: &[SavedCookie]) -> String {
+    let cookies: Vec<JsonValue> = cookies.iter().map(SavedCookie::to_json).collect();
+    let state = json!({ "cookies": cookies, "origins": [] });
+    serde_json::to_string_pretty(&state).unwrap_or_default()
+}
+
+/// Reads a Playwright storage state, or a bare array of its cookies.
+fn read_json(contents: &str) -> Result<Vec<SavedCookie>, String> {
+    let state: JsonValue = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+    let cookies = match &state {
+        JsonValue::Array(cookies) => cookies,
+        _ => state
+            .get("cookies")
+            .and_then(JsonValue::as_array)
+            .ok_or("expected a \"cookies\" array")?,
+    };
+    cookies.iter().map(SavedCookie::from_json).collect()
+}
+
+fn parse_url(url: &str) -> Result<Url, MagnusError> {
+    Url::parse(url).map_err(|e| {
+        MagnusError::new(
+            exception::arg_error(),
+            format!("invalid URL {}: {}", url, e),
+        )
+    })
+}
+
+/// `Rquest::HTTP::CookieJar`. Clients given the same jar share its cookies:
+/// `jar = CookieJar.new; HTTP.cookies(jar).post(login_url, ...)`.
+#[magnus::wrap(class = "Rquest::HTTP::CookieJar")]
+pub(crate) struct RbCookieJar(Arc<CookieJar>);
+
+impl RbCookieJar {
+    pub(crate) fn new(jar: Arc<CookieJar>) -> Self {
+        Self(jar)
+    }
+
+    fn rb_new() -> Self {
+        Self::new(Arc::default())
+    }
+
+    pub(crate) fn jar(&self) -> Arc<CookieJar> {
+        self.0.clone()
+    }
+
+    /// The cookies that would be sent to `url`, as a Hash of name to value.
+    fn cookies_for(&self, url: String) -> Result<RHash, MagnusError> {
+        let url = parse_url(&url)?;
+        let cookies = RHash::new();
+        for (name, value) in self.0.read().get_request_values(&url) {
+            cookies.aset(name, value)?;
+        }
+        Ok(cookies)
+    }
+
+    /// Stores a cookie as if `url` had sent it in a `Set-Cookie` header:
+    /// `add("https://example.com", "session=abc; Path=/; HttpOnly")`.
+    fn add(&self, url: String, cookie: String) -> Result<(), MagnusError> {
+        let url = parse_url(&url)?;
+        self.0.write().parse(&cookie, &url).map_err(|e| {
+            MagnusError::new(exception::arg_error(), format!("invalid cookie: {}", e))
+        })?;
+        Ok(())
+    }
+
+    /// Removes the cookies named `name` that would be sent to `url`. Returns
+    /// whether any were removed.
+    fn delete(&self, url: String, name: String) -> Result<bool, MagnusError> {
+        let url = parse_url(&url)?;
+        let mut store = self.0.write();
+
+        let before = store.iter_any().count();
+        let kept: Vec<_> = store
+            .iter_any()
+            .filter(|cookie| !(cookie.name() == name && cookie.matches(&url)))
+            .cloned()
+            .collect();
+        if kept.len() == before {
+            return Ok(false);
+        }
+
+        *store = match Store::from_cookies(kept.into_iter().map(Ok::<_, Infallible>), false) {
+            Ok(store) => store,
+            Err(never) => match never {},
+        };
+        Ok(true)
+    }
+
+    fn clear(&self) {
+        self.0.write().clear();
+    }
+
+    /// Writes the jar's cookies to `path`: `save(path)` writes a Netscape
+    /// cookie file, `save(path, format: :json)` a Playwright storage state.
+    /// Expired cookies are left out; session cookies are kept.
+    fn save(&self, args: &[Value]) -> Result<(), MagnusError> {
+        let args = scan_args::<(String,), (), (), (), RHash, ()>(args)?;
+        let (path,) = args.required;
+        let kwargs = get_kwargs::<_, (), (Option<Value>,), ()>(args.keywords, &[], &["format"])?;
+        let format = match kwargs.optional.0 {
+            Some(format) => CookieFormat::from_value(format)?,
+            None => CookieFormat::Netscape,
+        };
+
+        let cookies: Vec<SavedCookie> = self
+            .0
+            .read()
+            .iter_unexpired()
+            .filter_map(SavedCookie::from_cookie)
+            .collect();
+        let contents = match format {
+            CookieFormat::Netscape => write_netscape(&cookies),
+            CookieFormat::Json => write_json(&cookies),
+        };
+
+        std::fs::write(&path, contents).map_err(|e| {
+            MagnusError::new(
+                exception::io_error(),
+                format!("failed to write {}: {}", path, e),
+            )
+        })
+    }
+
+    /// Reads a jar saved by [`Self::save`], curl or Playwright. The format is
+    /// detected from the contents unless given with `format:`. Cookies that
+    /// have expired since are skipped.
+    fn load(args: &[Value]) -> Result<Self, MagnusError> {
+        let args = scan_args::<(String,), (), (), (), RHash, ()>(args)?;
+        let (path,) = args.required;
+        let kwargs = get_kwargs::<_, (), (Option<Value>,), ()>(args.keywords, &[], &["format"])?;
+
+        let contents = std::fs::read_to_string(&path).map_err(|e| {
+            MagnusError::new(
+                exception::io_error(),
+                format!("failed to read {}: {}", path, e),
+            )
+        })?;
+        let format = match kwargs.optional.0 {
+            Some(format) => CookieFormat::from_value(format)?,
+            None => CookieFormat::detect(&contents),
+        };
+
+        let invalid = |message: String| {
+            MagnusError::new(
+                exception::arg_error(),
+                format!("invalid cookie file {}: {}", path, message),
+            )
+        };
+        let cookies = match format {
+            CookieFormat::Netscape => read_netscape(&contents),
+            CookieFormat::Json => read_json(&contents),
+        }
+        .map_err(invalid)?;
+
+        let jar = CookieJar::default();
+        let now = now();
+        {
+            let mut store = jar.write();
+            for cookie in cookies.iter().filter(|cookie| !cookie.is_expired(now)) {
+                let url = cookie.url().map_err(invalid)?;
+                store
+                    .parse(&cookie.set_cookie(now), &url)
+                    .map_err(|e| invalid(format!("cookie {}: {}", cookie.name, e)))?;
+            }
+        }
+        Ok(Self::new(Arc::new(jar)))
+    }
+}
+
+pub(crate) fn define_cookie_jar(ruby: &Ruby, http_module: RModule) -> Result<(), MagnusError> {
+    let jar_class = http_module.define_class("CookieJar", ruby.class_object())?;
+    jar_class.define_singleton_method("new", function!(RbCookieJar::rb_new, 0))?;
+    jar_class.define_method("cookies_for", method!(RbCookieJar::cookies_for, 1))?;
+    jar_class.define_method("add", method!(RbCookieJar::add, 2))?;
+    jar_class.define_method("delete", method!(RbCookieJar::delete, 2))?;
+    jar_class.define_method("clear", method!(RbCookieJar::clear, 0))?;
+    jar_class.define_method("save", method!(RbCookieJar::save, -1))?;
+    jar_class.define_singleton_method("load", function!(RbCookieJar::load, -1))?;
+    Ok(())
+}
@@ -0,0 +1,187 @@
+use magnus::{Error as MagnusError, RArray, Symbol, TryConvert, Value, exception};
+use rquest_util::Emulation as RquestEmulation;
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::num::Wrapping;
+
+/// Every emulation shipped by rquest-util, under the name used from Ruby. The
+/// tests check it against rquest-util's variants.
+pub(crate) const EMULATIONS: &[(&str, RquestEmulation)] = &[
+    ("chrome100", RquestEmulation::Chrome100),
+    ("chrome101", RquestEmulation::Chrome101),
+    ("chrome104", RquestEmulation::Chrome104),
+    ("chrome105", RquestEmulation::Chrome105),
+    ("chrome106", RquestEmulation::Chrome106),
+    ("chrome107", RquestEmulation::Chrome107),
+    ("chrome108", RquestEmulation::Chrome108),
+    ("chrome109", RquestEmulation::Chrome109),
+    ("chrome110", RquestEmulation::Chrome110),
+    ("chrome114", RquestEmulation::Chrome114),
+    ("chrome116", RquestEmulation::Chrome116),
+    ("chrome117", RquestEmulation::Chrome117),
+    ("chrome118", RquestEmulation::Chrome118),
+    ("chrome119", RquestEmulation::Chrome119),
+    ("chrome120", RquestEmulation::Chrome120),
+    ("chrome123", RquestEmulation::Chrome123),
+    ("chrome124", RquestEmulation::Chrome124),
+    ("chrome126", RquestEmulation::Chrome126),
+    ("chrome127", RquestEmulation::Chrome127),
+    ("chrome128", RquestEmulation::Chrome128),
+    ("chrome129", RquestEmulation::Chrome129),
+    ("chrome130", RquestEmulation::Chrome130),
+    ("chrome131", RquestEmulation::Chrome131),
+    ("chrome132", RquestEmulation::Chrome132),
+    ("chrome133", RquestEmulation::Chrome133),
+    ("chrome134", RquestEmulation::Chrome134),
+    ("edge101", RquestEmulation::Edge101),
+    ("edge122", RquestEmulation::Edge122),
+    ("edge127", RquestEmulation::Edge127),
+    ("edge131", RquestEmulation::Edge131),
+    ("edge134", RquestEmulation::Edge134),
+    ("firefox109", RquestEmulation::Firefox109),
+    ("firefox117", RquestEmulation::Firefox117),
+    ("firefox128", RquestEmulation::Firefox128),
+    ("firefox133", RquestEmulation::Firefox133),
+    ("firefox135", RquestEmulation::Firefox135),
+    ("firefox_private135", RquestEmulation::FirefoxPrivate135),
+    ("firefox_android135", RquestEmulation::FirefoxAndroid135),
+    ("firefox136", RquestEmulation::Firefox136),
+    ("firefox_private136", RquestEmulation::FirefoxPrivate136),
+    ("safari_15_3", RquestEmulation::Safari15_3),
+    ("safari_15_5", RquestEmulation::Safari15_5),
+    ("safari_15_6_1", RquestEmulation::Safari15_6_1),
+    ("safari16", RquestEmulation::Safari16),
+    ("safari_16_5", RquestEmulation::Safari16_5),
+    ("safari_17_0", RquestEmulation::Safari17_0),
+    ("safari_17_2_1", RquestEmulation::Safari17_2_1),
+    ("safari_17_4_1", RquestEmulation::Safari17_4_1),
+    ("safari_17_5", RquestEmulation::Safari17_5),
+    ("safari18", RquestEmulation::Safari18),
+    ("safari_18_2", RquestEmulation::Safari18_2),
+    ("safari_18_3", RquestEmulation::Safari18_3),
+    ("safari_18_3_1", RquestEmulation::Safari18_3_1),
+    ("safari_ipad18", RquestEmulation::SafariIPad18),
+    ("safari_ios_16_5", RquestEmulation::SafariIos16_5),
+    ("safari_ios_17_2", RquestEmulation::SafariIos17_2),
+    ("safari_ios_17_4_1", RquestEmulation::SafariIos17_4_1),
+    ("safari_ios_18_1_1", RquestEmulation::SafariIos18_1_1),
+    ("okhttp_3_9", RquestEmulation::OkHttp3_9),
+    ("okhttp_3_11", RquestEmulation::OkHttp3_11),
+    ("okhttp_3_13", RquestEmulation::OkHttp3_13),
+    ("okhttp_3_14", RquestEmulation::OkHttp3_14),
+    ("okhttp_4_9", RquestEmulation::OkHttp4_9),
+    ("okhttp_4_10", RquestEmulation::OkHttp4_10),
+    ("okhttp_4_12", RquestEmulation::OkHttp4_12),
+    ("okhttp5", RquestEmulation::OkHttp5),
+];
+
+/// Strips separators so `:chrome_134`, `"Chrome134"` and `"safari_ios_17.4.1"`
+/// all match their canonical names.
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, '_' | '-' | '.' | ' '))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Looks up an emulation by name.
+pub(crate) fn find(name: &str) -> Option<RquestEmulation> {
+    let wanted = normalize(name);
+    EMULATIONS
+        .iter()
+        .find(|(candidate, _)| normalize(candidate) == wanted)
+        .map(|(_, emulation)| *emulation)
+}
+
+/// Converts a Ruby Symbol or String into an emulation, raising `ArgumentError`
+/// for unknown names.
+pub(crate) fn from_value(value: Value) -> Result<RquestEmulation, MagnusError> {
+    let name = match Symbol::from_value(value) {
+        Some(symbol) => symbol.name()?.into_owned(),
+        None => String::try_convert(value)?,
+    };
+
+    find(&name).ok_or_else(|| {
+        MagnusError::new(
+            exception::arg_error(),
+            format!(
+                "unknown emulation: {} (see Rquest::HTTP.emulations for valid names)",
+                name
+            ),
+        )
+    })
+}
+
+/// The names of all supported emulations, as an array of symbols.
+pub(crate) fn names() -> RArray {
+    let names = RArray::with_capacity(EMULATIONS.len());
+    for (name, _) in EMULATIONS {
+        let _ = names.push(Symbol::new(name));
+    }
+    names
+}
+
+// Fast random implementation similar to rquest-util crate
+fn fast_random() -> u64 {
+    thread_local! {
+        static RNG: Cell<Wrapping<u64>> = Cell::new(Wrapping(seed()));
+    }
+
+    #[inline]
+    fn seed() -> u64 {
+        let seed = RandomState::new();
+        let mut out = 0;
+        let mut cnt = 0;
+        while out == 0 {
+            cnt += 1;
+            let mut hasher = seed.build_hasher();
+            hasher.write_usize(cnt);
+            out = hasher.finish();
+        }
+        out
+    }
+
+    RNG.with(|rng| {
+        let mut n = rng.get();
+        debug_assert_ne!(n.0, 0);
+        n ^= n >> 12;
+        n ^= n << 25;
+        n ^= n >> 27;
+        rng.set(n);
+        n.0.wrapping_mul(0x2545f4914f6cdd1d)
+    })
+}
+
+pub(crate) fn get_random_desktop_emulation() -> RquestEmulation {
+    let browsers = [
+        RquestEmulation::Chrome134,
+        RquestEmulation::Chrome128,
+        RquestEmulation::Chrome101,
+        RquestEmulation::Firefox135,
+        RquestEmulation::Safari17_0,
+    ];
+
+    let index = (fast_random() as usize) % browsers.len();
+    browsers[index]
+}
+
+pub(crate) fn get_random_mobile_emulation() -> RquestEmulation {
+    let browsers = [
+        RquestEmulation::SafariIos17_4_1,
+        RquestEmulation::SafariIos17_2,
+        RquestEmulation::SafariIos16_5,
+        RquestEmulation::FirefoxAndroid135,
+    ];
+
+    let index = (fast_random() as usize) % browsers.len();
+    browsers[index]
+}
+
+pub(crate) fn get_random_emulation() -> RquestEmulation {
+    if fast_random() % 100 < 50 {
+        get_random_desktop_emulation()
+    } else {
+        get_random_mobile_emulation()
+    }
+}
@@ -0,0 +1,249 @@
+use crate::redirect::{self, Hop};
+use magnus::prelude::*;
+use magnus::value::Lazy;
+use magnus::{Error as MagnusError, Exception, ExceptionClass, RModule, Ruby, Symbol, Value};
+use rquest::Error as RquestError;
+use std::error::Error as StdError;
+
+pub(crate) fn http_module(ruby: &Ruby) -> RModule {
+    ruby.class_object()
+        .const_get::<_, RModule>("Rquest")
+        .and_then(|rquest| rquest.const_get::<_, RModule>("HTTP"))
+        .expect("Rquest::HTTP is not defined")
+}
+
+macro_rules! error_class {
+    ($static:ident, $name:literal) => {
+        static $static: Lazy<ExceptionClass> = Lazy::new(|ruby| {
+            http_module(ruby).const_get($name).expect(concat!(
+                "Rquest::HTTP::",
+                $name,
+                " is not defined"
+            ))
+        });
+    };
+}
+
+error_class!(TIMEOUT_ERROR, "TimeoutError");
+error_class!(CONNECT_TIMEOUT_ERROR, "ConnectTimeoutError");
+error_class!(READ_TIMEOUT_ERROR, "ReadTimeoutError");
+error_class!(CONNECTION_ERROR, "ConnectionError");
+error_class!(PROXY_ERROR, "ProxyError");
+error_class!(TLS_ERROR, "TLSError");
+error_class!(REDIRECT_ERROR, "RedirectError");
+error_class!(TOO_MANY_REDIRECTS_ERROR, "TooManyRedirectsError");
+error_class!(BODY_DECODE_ERROR, "BodyDecodeError");
+error_class!(REQUEST_ERROR, "RequestError");
+error_class!(PARSE_ERROR, "ParseError");
+
+/// Defines the `Rquest::HTTP::Error` hierarchy, mirroring http.rb's error classes.
+pub(crate) fn define_errors(ruby: &Ruby, http_module: RModule) -> Result<(), MagnusError> {
+    let error = http_module.define_error("Error", ruby.exception_standard_error())?;
+    let _: Value = error.funcall(
+        "attr_reader",
+        (
+            Symbol::new("url"),
+            Symbol::new("request_method"),
+            Symbol::new("reason"),
+        ),
+    )?;
+
+    let timeout = http_module.define_error("TimeoutError", error)?;
+    http_module.define_error("ConnectTimeoutError", timeout)?;
+    http_module.define_error("ReadTimeoutError", timeout)?;
+    let connection = http_module.define_error("ConnectionError", error)?;
+    http_module.define_error("ProxyError", connection)?;
+    http_module.define_error("TLSError", connection)?;
+    let redirect = http_module.define_error("RedirectError", error)?;
+    let _: Value = redirect.funcall("attr_reader", (Symbol::new("history"),))?;
+    http_module.define_error("TooManyRedirectsError", redirect)?;
+    http_module.define_error("BodyDecodeError", error)?;
+    http_module.define_error("RequestError", error)?;
+    http_module.define_error("ParseError", error)?;
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorKind {
+    Timeout,
+    ConnectTimeout,
+    ReadTimeout,
+    Connection,
+    Proxy,
+    Tls,
+    Redirect,
+    TooManyRedirects,
+    BodyDecode,
+    Request,
+    Parse,
+}
+
+impl ErrorKind {
+    fn class(self, ruby: &Ruby) -> ExceptionClass {
+        let class = match self {
+            ErrorKind::Timeout => &TIMEOUT_ERROR,
+            ErrorKind::ConnectTimeout => &CONNECT_TIMEOUT_ERROR,
+            ErrorKind::ReadTimeout => &READ_TIMEOUT_ERROR,
+            ErrorKind::Connection => &CONNECTION_ERROR,
+            ErrorKind::Proxy => &PROXY_ERROR,
+            ErrorKind::Tls => &TLS_ERROR,
+            ErrorKind::Redirect => &REDIRECT_ERROR,
+            ErrorKind::TooManyRedirects => &TOO_MANY_REDIRECTS_ERROR,
+            ErrorKind::BodyDecode => &BODY_DECODE_ERROR,
+            ErrorKind::Request => &REQUEST_ERROR,
+            ErrorKind::Parse => &PARSE_ERROR,
+        };
+        ruby.get_inner(class)
+    }
+}
+
+/// Walks the `source()` chain of an error, starting with the error itself.
+fn chain(err: &(dyn StdError + 'static)) -> impl Iterator<Item = &(dyn StdError + 'static)> {
+    std::iter::successors(Some(err), |e| e.source())
+}
+
+fn chain_mentions(err: &RquestError, needles: &[&str]) -> bool {
+    chain(err).any(|e| {
+        let message = e.to_string().to_lowercase();
+        needles.iter().any(|needle| message.contains(needle))
+    })
+}
+
+/// Picks the Ruby error class for an rquest error.
+///
+/// rquest has no predicates for proxy or TLS failures, so those are recognised
+/// from the messages of the underlying connector errors.
+pub(crate) fn classify(err: &RquestError) -> ErrorKind {
+    if err.is_timeout() && err.is_connect() {
+        ErrorKind::ConnectTimeout
+    } else if err.is_timeout() {
+        ErrorKind::Timeout
+    } else if err.is_redirect() {
+        ErrorKind::Redirect
+    } else if err.is_decode() || err.is_body() {
+        ErrorKind::BodyDecode
+    } else if err.is_connect() {
+        if chain_mentions(err, &["proxy", "tunnel"]) {
+            ErrorKind::Proxy
+        } else if chain_mentions(err, &["tls", "ssl", "certificate", "handshake"]) {
+            ErrorKind::Tls
+        } else {
+            ErrorKind::Connection
+        }
+    } else {
+        ErrorKind::Request
+    }
+}
+
+/// Formats an error together with all of its sources, since rquest's `Display`
+/// only shows the outermost layer ("error sending request").
+fn describe(err: &RquestError) -> String {
+    chain(err)
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+/// Builds an instance of `kind`'s error class carrying the request context.
+pub(crate) fn new_error(
+    ruby: &Ruby,
+    kind: ErrorKind,
+    message: String,
+    method: Option<&str>,
+    url: &str,
+    reason: Option<String>,
+) -> MagnusError {
+    let exception = match kind.class(ruby).new_instance((message,)) {
+        Ok(exception) => exception,
+        Err(e) => return e,
+    };
+
+    let ivars = [
+        ("@url", Some(url.to_string())),
+        ("@request_method", method.map(str::to_string)),
+        ("@reason", reason),
+    ];
+    for (name, value) in ivars {
+        if let Err(e) = exception.ivar_set(name, value) {
+            return e;
+        }
+    }
+
+    exception.into()
+}
+
+/// A failed request, resolved to its error class but not yet converted, since
+/// building the Ruby exception needs the GVL.
+pub(crate) enum Failure {
+    Rquest(ErrorKind, RquestError),
+    /// A redirect that can't be followed, with the redirects followed so far,
+    /// including the one that failed.
+    Redirect(ErrorKind, String, Vec<Hop>),
+    /// An exception raised by Ruby code called during the request.
+    Ruby(MagnusError),
+}
+
+impl Failure {
+    pub(crate) fn into_error(self, method: &str, url: &str) -> MagnusError {
+        match self {
+            Failure::Rquest(kind, err) => rquest_error_to_magnus_error(err, kind, method, url),
+            Failure::Redirect(kind, message, hops) => {
+                redirect_error(kind, message, &hops, method, url)
+            }
+            Failure::Ruby(error) => error,
+        }
+    }
+}
+
+/// Converts a failed rquest call into an instance of `kind`'s error class,
+/// usually picked by [`classify`].
+fn rquest_error_to_magnus_error(
+    err: RquestError,
+    kind: ErrorKind,
+    method: &str,
+    url: &str,
+) -> MagnusError {
+    let ruby = Ruby::get().expect("rquest errors are raised from a Ruby thread");
+    let url = err
+        .url()
+        .map(|u| u.to_string())
+        .unwrap_or_else(|| url.to_string());
+    let reason = describe(&err);
+
+    new_error(
+        &ruby,
+        kind,
+        format!("HTTP request failed: {}", reason),
+        Some(method),
+        &url,
+        Some(reason),
+    )
+}
+
+/// Builds a redirect error carrying the redirect chain as `history`.
+fn redirect_error(
+    kind: ErrorKind,
+    message: String,
+    hops: &[Hop],
+    method: &str,
+    url: &str,
+) -> MagnusError {
+    let ruby = Ruby::get().expect("redirect errors are raised from a Ruby thread");
+    let error = new_error(
+        &ruby,
+        kind,
+        format!("HTTP request failed: {}", message),
+        Some(method),
+        url,
+        Some(message),
+    );
+
+    let Some(exception) = error.value().and_then(Exception::from_value) else {
+        return error;
+    };
+    match redirect::history(hops).and_then(|history| exception.ivar_set("@history", history)) {
+        Ok(()) => error,
+        Err(e) => e,
+    }
+}
@@ -0,0 +1,291 @@
+use crate::upload::{self, CHUNK_SIZE, Feeder, Upload};
+use crate::{MAX_NESTING, nesting_error};
+use bytes::{Bytes, BytesMut};
+use futures_util::stream::{self, Stream};
+use magnus::gc::Marker;
+use magnus::r_hash::ForEach;
+use magnus::scan_args::{get_kwargs, scan_args};
+use magnus::value::{Opaque, ReprValue};
+use magnus::{
+    DataTypeFunctions, Error as MagnusError, Module, Object, RArray, RHash, RModule, RString, Ruby,
+    TryConvert, Value, exception, function, method,
+};
+use rquest::multipart::{Form as MultipartForm, Part};
+use rquest::{Body, RequestBuilder};
+use std::io;
+use tokio::io::AsyncReadExt;
+
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// `Rquest::HTTP::FormData::File`, a file part for a multipart form:
+/// `FormData::File.new(path_or_io, filename: "a.txt", content_type: "text/plain")`.
+#[magnus::wrap(class = "Rquest::HTTP::FormData::File", mark)]
+pub(crate) struct FormFile {
+    /// A path String or an IO.
+    source: Opaque<Value>,
+    filename: Option<String>,
+    content_type: Option<String>,
+}
+
+impl DataTypeFunctions for FormFile {
+    fn mark(&self, marker: &Marker) {
+        marker.mark(self.source);
+    }
+}
+
+impl FormFile {
+    fn rb_new(args: &[Value]) -> Result<Self, MagnusError> {
+        let args = scan_args::<(Value,), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (source,) = args.required;
+        let (options,) = args.optional;
+
+        if RString::from_value(source).is_none() && !source.respond_to("read", false)? {
+            return Err(MagnusError::new(
+                exception::type_error(),
+                "expected a path or an IO",
+            ));
+        }
+
+        let (filename, content_type) = match options {
+            Some(options) => {
+                get_kwargs::<_, (), (Option<String>, Option<String>), ()>(
+                    options,
+                    &[],
+                    &["filename", "content_type"],
+                )?
+                .optional
+            }
+            None => (None, None),
+        };
+
+        Ok(Self {
+            source: source.into(),
+            filename,
+            content_type,
+        })
+    }
+
+    fn filename(&self) -> Option<String> {
+        self.filename.clone()
+    }
+
+    fn content_type(&self) -> Option<String> {
+        self.content_type.clone()
+    }
+}
+
+pub(crate) fn define_form_data(ruby: &Ruby, http_module: RModule) -> Result<(), MagnusError> {
+    let form_data = http_module.define_module("FormData")?;
+    let file_class = form_data.define_class("File", ruby.class_object())?;
+    file_class.define_singleton_method("new", function!(FormFile::rb_new, -1))?;
+    file_class.define_method("filename", method!(FormFile::filename, 0))?;
+    file_class.define_method("content_type", method!(FormFile::content_type, 0))?;
+    Ok(())
+}
+
+enum FileSource {
+    Path(String),
+    Io(Upload),
+}
+
+struct FilePart {
+    source: FileSource,
+    filename: Option<String>,
+    content_type: Option<String>,
+}
+
+impl FilePart {
+    fn from_form_file(file: &FormFile) -> Result<Self, MagnusError> {
+        let ruby = Ruby::get().expect("forms are built on a Ruby thread");
+        let source = ruby.get_inner(file.source);
+
+        let (source, default_filename) = match RString::from_value(source) {
+            Some(path) => {
+                let path = path.to_string()?;
+                let filename = upload::basename(&path);
+                (FileSource::Path(path), filename)
+            }
+            None => Self::io_source(source)?,
+        };
+
+        Ok(Self {
+            source,
+            filename: file.filename.clone().or(default_filename),
+            content_type: file.content_type.clone(),
+        })
+    }
+
+    fn from_io(upload: Upload) -> Result<Self, MagnusError> {
+        let filename = upload.filename()?;
+        Ok(Self {
+            source: FileSource::Io(upload),
+            filename,
+            content_type: None,
+        })
+    }
+
+    fn io_source(io: Value) -> Result<(FileSource, Option<String>), MagnusError> {
+        let upload = Upload::from_value(io)?
+            .ok_or_else(|| MagnusError::new(exception::type_error(), "expected a path or an IO"))?;
+        let filename = upload.filename()?;
+        Ok((FileSource::Io(upload), filename))
+    }
+
+    /// Builds the multipart part, streaming the file rather than reading it
+    /// into memory.
+    fn into_part(self) -> Result<(Part, Option<Feeder>), MagnusError> {
+        let (part, feeder) = match self.source {
+            FileSource::Path(path) => {
+                let file = std::fs::File::open(&path).map_err(|e| {
+                    MagnusError::new(exception::io_error(), format!("{}: {}", path, e))
+                })?;
+                let length = file.metadata().map(|metadata| metadata.len()).ok();
+                let body = Body::wrap_stream(read_file(file));
+                let part = match length {
+                    Some(length) => Part::stream_with_length(body, length),
+                    None => Part::stream(body),
+                };
+                (part, None)
+            }
+            FileSource::Io(upload) => {
+                let (body, size, feeder) = upload.into_body();
+                let part = match size {
+                    Some(size) => Part::stream_with_length(body, size),
+                    None => Part::stream(body),
+                };
+                (part, Some(feeder))
+            }
+        };
+
+        let content_type = self.content_type.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE);
+        let mut part = part.mime_str(content_type).map_err(|_| {
+            MagnusError::new(
+                exception::arg_error(),
+                format!("invalid content type: {}", content_type),
+            )
+        })?;
+        if let Some(filename) = self.filename {
+            part = part.file_name(filename);
+        }
+
+        Ok((part, feeder))
+    }
+}
+
+/// Reads a file on the runtime's blocking pool, without the GVL.
+fn read_file(file: std::fs::File) -> impl Stream<Item = io::Result<Bytes>> {
+    stream::try_unfold(tokio::fs::File::from_std(file), |mut file| async move {
+        let mut chunk = BytesMut::with_capacity(CHUNK_SIZE);
+        match file.read_buf(&mut chunk).await? {
+            0 => Ok(None),
+            _ => Ok(Some((chunk.freeze(), file))),
+        }
+    })
+}
+
+enum Field {
+    Text(String),
+    File(FilePart),
+}
+
+/// The `form:` option, flattened into fields with Rack-style nested keys:
+/// `{ user: { tags: ["a"] } }` becomes `user[tags][]=a`.
+pub(crate) struct Form {
+    fields: Vec<(String, Field)>,
+}
+
+impl Form {
+    pub(crate) fn from_hash(hash: RHash) -> Result<Self, MagnusError> {
+        let mut form = Self { fields: Vec::new() };
+        form.add_hash(None, hash, 1)?;
+        Ok(form)
+    }
+
+    /// Adds the fields of `hash`, which is nested `depth` deep.
+    fn add_hash(
+        &mut self,
+        prefix: Option<&str>,
+        hash: RHash,
+        depth: usize,
+    ) -> Result<(), MagnusError> {
+        hash.foreach(|key: Value, value: Value| {
+            let key = key.funcall::<_, _, String>("to_s", ())?;
+            let name = match prefix {
+                Some(prefix) => format!("{}[{}]", prefix, key),
+                None => key,
+            };
+            self.add(name, value, depth)?;
+            Ok(ForEach::Continue)
+        })
+    }
+
+    /// Adds a field, or the fields of a Hash or Array nested in a container
+    /// `depth` deep.
+    fn add(&mut self, name: String, value: Value, depth: usize) -> Result<(), MagnusError> {
+        let container = RHash::from_value(value).is_some() || RArray::from_value(value).is_some();
+        if container && depth >= MAX_NESTING {
+            return Err(nesting_error(&name));
+        }
+
+        if let Some(hash) = RHash::from_value(value) {
+            return self.add_hash(Some(&name), hash, depth + 1);
+        }
+        if let Some(array) = RArray::from_value(value) {
+            for value in array.to_vec::<Value>()? {
+                self.add(format!("{}[]", name), value, depth + 1)?;
+            }
+            return Ok(());
+        }
+
+        let field = if let Ok(file) = <&FormFile>::try_convert(value) {
+            Field::File(FilePart::from_form_file(file)?)
+        } else if value.is_nil() {
+            Field::Text(String::new())
+        } else if let Some(upload) = Upload::from_value(value)? {
+            Field::File(FilePart::from_io(upload)?)
+        } else {
+            Field::Text(value.funcall("to_s", ())?)
+        };
+
+        self.fields.push((name, field));
+        Ok(())
+    }
+
+    /// Sets the form as the body of `req`: URL-encoded when every field is
+    /// text, multipart otherwise. Returns the feeders for IO parts.
+    pub(crate) fn attach(
+        self,
+        req: RequestBuilder,
+    ) -> Result<(RequestBuilder, Vec<Feeder>), MagnusError> {
+        if self
+            .fields
+            .iter()
+            .all(|(_, field)| matches!(field, Field::Text(_)))
+        {
+            let pairs: Vec<(String, String)> = self
+                .fields
+                .into_iter()
+                .filter_map(|(name, field)| match field {
+                    Field::Text(value) => Some((name, value)),
+                    Field::File(_) => None,
+                })
+                .collect();
+            return Ok((req.form(&pairs), Vec::new()));
+        }
+
+        let mut form = MultipartForm::new();
+        let mut feeders = Vec::new();
+        for (name, field) in self.fields {
+            form = match field {
+                Field::Text(value) => form.text(name, value),
+                Field::File(file) => {
+                    let (part, feeder) = file.into_part()?;
+                    feeders.extend(feeder);
+                    form.part(name, part)
+                }
+            };
+        }
+
+        Ok((req.multipart(form), feeders))
+    }
+}
@@ -0,0 +1,128 @@
+use crate::get_runtime;
+use magnus::{Error as MagnusError, Ruby};
+use std::ffi::c_void;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use tokio::sync::Notify;
+
+/// A closure handed to Ruby's GVL functions, along with the slot its result is
+/// written to.
+struct Call<F, R> {
+    func: Option<F>,
+    result: Option<std::thread::Result<R>>,
+}
+
+impl<F, R> Call<F, R>
+where
+    F: FnOnce() -> R,
+{
+    fn new(func: F) -> Self {
+        Self {
+            func: Some(func),
+            result: None,
+        }
+    }
+
+    fn as_ptr(&mut self) -> *mut c_void {
+        self as *mut Self as *mut c_void
+    }
+
+    /// Entry point passed to Ruby. Panics are caught here, since they must not
+    /// unwind through Ruby's C frames.
+    unsafe extern "C" fn trampoline(data: *mut c_void) -> *mut c_void {
+        let call = unsafe { &mut *(data as *mut Self) };
+        if let Some(func) = call.func.take() {
+            call.result = Some(panic::catch_unwind(AssertUnwindSafe(func)));
+        }
+        ptr::null_mut()
+    }
+
+    /// The closure's result, or `None` if Ruby never ran it.
+    fn into_result(self) -> Option<R> {
+        self.result
+            .map(|result| result.unwrap_or_else(|payload| panic::resume_unwind(payload)))
+    }
+}
+
+/// Runs `func` with the GVL released, so other Ruby threads keep running.
+///
+/// `cancel` is notified when Ruby wants the thread back (`Thread#kill`,
+/// `Thread#raise`, `Timeout.timeout`, signals). Returns `None` if `func` was not
+/// run because an interrupt was already pending.
+fn without_gvl<F, R>(func: F, cancel: &Notify) -> Option<R>
+where
+    F: FnOnce() -> R,
+{
+    unsafe extern "C" fn unblock(data: *mut c_void) {
+        let cancel = unsafe { &*(data as *const Notify) };
+        cancel.notify_one();
+    }
+
+    let mut call = Call::new(func);
+
+    // The `2` variant returns instead of raising when an interrupt is pending,
+    // so no Ruby exception ever unwinds through these Rust frames.
+    unsafe {
+        rb_sys::rb_thread_call_without_gvl2(
+            Some(Call::<F, R>::trampoline),
+            call.as_ptr(),
+            Some(unblock),
+            cancel as *const Notify as *mut c_void,
+        );
+    }
+
+    call.into_result()
+}
+
+/// Runs `func` with the GVL reacquired, from inside a [`block_on`] future.
+///
+/// Only valid on the thread that released the GVL, which is where `block_on`
+/// polls its future; tasks spawned onto the runtime must not call this. The
+/// runtime context is left for the duration, so `func` may itself make
+/// requests.
+pub(crate) fn with_gvl<F, R>(func: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let mut call = Call::new(func);
+
+    tokio::task::block_in_place(|| unsafe {
+        rb_sys::rb_thread_call_with_gvl(Some(Call::<F, R>::trampoline), call.as_ptr());
+    });
+
+    call.into_result()
+        .expect("rb_thread_call_with_gvl did not run the callback")
+}
+
+/// Drives `future` on the runtime without holding the GVL.
+///
+/// When Ruby interrupts the thread, the GVL is reacquired to handle the
+/// interrupt: if it raises (e.g. `Thread#kill` or `Timeout.timeout`), the
+/// future is dropped, cancelling the in-flight request; otherwise waiting
+/// resumes where it left off.
+pub(crate) fn block_on<F: Future>(future: F) -> Result<F::Output, MagnusError> {
+    let ruby = Ruby::get().expect("requests are made from a Ruby thread");
+    let rt = get_runtime();
+    let mut future = std::pin::pin!(future);
+
+    loop {
+        let cancel = Notify::new();
+        let output = without_gvl(
+            || {
+                rt.block_on(async {
+                    tokio::select! {
+                        output = &mut future => Some(output),
+                        _ = cancel.notified() => None,
+                    }
+                })
+            },
+            &cancel,
+        );
+
+        match output.flatten() {
+            Some(output) => return Ok(output),
+            None => ruby.thread_check_ints()?,
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use magnus::r_hash::ForEach;
+use magnus::scan_args::scan_args;
+use magnus::value::ReprValue;
+use magnus::{
+    Error as MagnusError, Module, Object, RArray, RHash, RModule, RString, Ruby, Symbol,
+    TryConvert, Value, exception, function, method,
+};
+use rquest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// `Rquest::HTTP::Headers`, a case-insensitive multimap of header fields that
+/// mirrors http.rb's `HTTP::Headers`. Repeated fields such as `Set-Cookie`
+/// keep every value.
+#[magnus::wrap(class = "Rquest::HTTP::Headers")]
+pub(crate) struct RbHeaders(RwLock<HeaderMap>);
+
+/// Converts a header name given as a String or Symbol. Symbols name headers
+/// the Ruby way: `:user_agent` is `User-Agent`.
+pub(crate) fn header_name(name: Value) -> Result<HeaderName, MagnusError> {
+    let name = name_string(name)?;
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+        MagnusError::new(
+            exception::arg_error(),
+            format!("invalid header name: {:?}", name),
+        )
+    })
+}
+
+fn name_string(name: Value) -> Result<String, MagnusError> {
+    match Symbol::from_value(name) {
+        Some(symbol) => Ok(symbol
+            .name()?
+            .split('_')
+            .map(capitalize)
+            .collect::<Vec<_>>()
+            .join("-")),
+        None => name.funcall("to_s", ()),
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// A header name in the canonical form http.rb gives it: `Content-Type`.
+fn canonical(name: &HeaderName) -> String {
+    name.as_str()
+        .split('-')
+        .map(capitalize)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Converts the values of header `name`: a value, or an Array of them. Values
+/// containing CR or LF are rejected, so they can't inject headers.
+fn header_values(name: &HeaderName, value: Value) -> Result<Vec<HeaderValue>, MagnusError> {
+    let values = match RArray::from_value(value) {
+        Some(array) => array.to_vec::<Value>()?,
+        None => vec![value],
+    };
+
+    values
+        .into_iter()
+        .map(|value| {
+            let value: String = value.funcall("to_s", ())?;
+            HeaderValue::from_str(&value).map_err(|_| {
+                MagnusError::new(
+                    exception::arg_error(),
+                    format!("invalid value for header {}", name),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Converts headers given as a Hash or `Headers`. Names repeated in a Hash,
+/// such as `"Accept"` and `:accept`, take the value given last.
+pub(crate) fn header_map(headers: Value) -> Result<HeaderMap, MagnusError> {
+    if let Ok(headers) = <&RbHeaders>::try_convert(headers) {
+        return Ok(headers.read().clone());
+    }
+
+    let mut map = HeaderMap::new();
+    RHash::try_convert(headers)?.foreach(|name: Value, value: Value| {
+        let name = header_name(name)?;
+        let values = header_values(&name, value)?;
+        map.remove(&name);
+        for value in values {
+            map.append(name.clone(), value);
+        }
+        Ok(ForEach::Continue)
+    })?;
+    Ok(map)
+}
+
+/// Merges `other` into `headers`, replacing every value of the fields it sets.
+pub(crate) fn merge(headers: &mut HeaderMap, other: &HeaderMap) {
+    for name in other.keys() {
+        headers.remove(name);
+    }
+    for (name, value) in other {
+        headers.append(name.clone(), value.clone());
+    }
+}
+
+/// A header value as a Ruby String. Values that aren't UTF-8 are returned as
+/// binary rather than dropped.
+fn value_string(value: &HeaderValue) -> RString {
+    match std::str::from_utf8(value.as_bytes()) {
+        Ok(value) => RString::new(value),
+        Err(_) => RString::from_slice(value.as_bytes()),
+    }
+}
+
+/// `nil` for no values, a String for one, and an Array for several, as
+/// http.rb's `Headers#[]` returns.
+fn collapse(mut values: Vec<RString>) -> Value {
+    match values.len() {
+        0 => Ruby::get()
+            .expect("headers are read from a Ruby thread")
+            .qnil()
+            .as_value(),
+        1 => values.remove(0).as_value(),
+        _ => RArray::from_slice(&values).as_value(),
+    }
+}
+
+impl RbHeaders {
+    pub(crate) fn new(map: HeaderMap) -> Self {
+        Self(RwLock::new(map))
+    }
+
+    /// `Headers.new`, `Headers.new("Accept" => "text/html")`, or a copy of
+    /// another `Headers`.
+    fn rb_new(args: &[Value]) -> Result<Self, MagnusError> {
+        let args = scan_args::<(), (Option<Value>,), (), (), (), ()>(args)?;
+        match args.optional.0 {
+            Some(headers) => Ok(Self::new(header_map(headers)?)),
+            None => Ok(Self::new(HeaderMap::new())),
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, HeaderMap> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HeaderMap> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Every value of `name`. Names that aren't valid header names have none.
+    fn values(&self, name: Value) -> Result<Vec<RString>, MagnusError> {
+        let Ok(name) = HeaderName::from_bytes(name_string(name)?.as_bytes()) else {
+            return Ok(Vec::new());
+        };
+        Ok(self.read().get_all(name).iter().map(value_string).collect())
+    }
+
+    /// Pairs of canonical name and value, copied so that blocks are free to
+    /// modify the headers while iterating.
+    fn pairs(&self) -> Vec<(String, RString)> {
+        self.read()
+            .iter()
+            .map(|(name, value)| (canonical(name), value_string(value)))
+            .collect()
+    }
+
+    fn aref(&self, name: Value) -> Result<Value, MagnusError> {
+        Ok(collapse(self.values(name)?))
+    }
+
+    fn get(&self, name: Value) -> Result<RArray, MagnusError> {
+        Ok(RArray::from_slice(&self.values(name)?))
+    }
+
+    /// Appends values to `name`, keeping those already present.
+    fn add(&self, name: Value, value: Value) -> Result<(), MagnusError> {
+        let name = header_name(name)?;
+        let values = header_values(&name, value)?;
+        let mut map = self.write();
+        for value in values {
+            map.append(name.clone(), value);
+        }
+        Ok(())
+    }
+
+    /// Replaces every value of `name`.
+    fn set(&self, name: Value, value: Value) -> Result<(), MagnusError> {
+        let name = header_name(name)?;
+        let values = header_values(&name, value)?;
+        let mut map = self.write();
+        map.remove(&name);
+        for value in values {
+            map.append(name.clone(), value);
+        }
+        Ok(())
+    }
+
+    fn delete(&self, name: Value) -> Result<(), MagnusError> {
+        let name = header_name(name)?;
+        self.write().remove(name);
+        Ok(())
+    }
+
+    fn include(&self, name: Value) -> Result<bool, MagnusError> {
+        Ok(!self.values(name)?.is_empty())
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.read().keys().map(canonical).collect()
+    }
+
+    /// Yields `name, value` for every value, repeating the name of fields that
+    /// have several.
+    fn each(&self) -> Result<(), MagnusError> {
+        let ruby = Ruby::get().expect("headers are read from a Ruby thread");
+        if !ruby.block_given() {
+            return Err(MagnusError::new(
+                exception::local_jump_error(),
+                "no block given (yield)",
+            ));
+        }
+
+        for (name, value) in self.pairs() {
+            ruby.yield_values::<_, Value>((name, value))?;
+        }
+        Ok(())
+    }
+
+    fn to_a(&self) -> Result<RArray, MagnusError> {
+        let pairs = RArray::new();
+        for (name, value) in self.pairs() {
+            pairs.push(RArray::from_slice(&[RString::new(&name), value]))?;
+        }
+        Ok(pairs)
+    }
+
+    /// A Hash of canonical names to a String, or an Array for fields with
+    /// several values.
+    fn to_h(&self) -> Result<RHash, MagnusError> {
+        let fields: Vec<(String, Vec<RString>)> = {
+            let map = self.read();
+            map.keys()
+                .map(|name| {
+                    let values = map.get_all(name).iter().map(value_string).collect();
+                    (canonical(name), values)
+                })
+                .collect()
+        };
+
+        let hash = RHash::new();
+        for (name, values) in fields {
+            hash.aset(name, collapse(values))?;
+        }
+        Ok(hash)
+    }
+
+    fn size(&self) -> usize {
+        self.read().len()
+    }
+
+    fn inspect(&self) -> Result<String, MagnusError> {
+        let hash: String = self.to_h()?.funcall("inspect", ())?;
+        Ok(format!("#<Rquest::HTTP::Headers {}>", hash))
+    }
+}
+
+pub(crate) fn define_headers(ruby: &Ruby, http_module: RModule) -> Result<(), MagnusError> {
+    let headers_class = http_module.define_class("Headers", ruby.class_object())?;
+    headers_class.include_module(ruby.module_enumerable())?;
+    headers_class.define_singleton_method("new", function!(RbHeaders::rb_new, -1))?;
+    headers_class.define_method("[]", method!(RbHeaders::aref, 1))?;
+    headers_class.define_method("get", method!(RbHeaders::get, 1))?;
+    headers_class.define_method("add", method!(RbHeaders::add, 2))?;
+    headers_class.define_method("set", method!(RbHeaders::set, 2))?;
+    headers_class.define_method("[]=", method!(RbHeaders::set, 2))?;
+    headers_class.define_method("delete", method!(RbHeaders::delete, 1))?;
+    headers_class.define_method("include?", method!(RbHeaders::include, 1))?;
+    headers_class.define_method("keys", method!(RbHeaders::keys, 0))?;
+    headers_class.define_method("each", method!(RbHeaders::each, 0))?;
+    headers_class.define_method("to_a", method!(RbHeaders::to_a, 0))?;
+    headers_class.define_method("to_h", method!(RbHeaders::to_h, 0))?;
+    headers_class.define_method("size", method!(RbHeaders::size, 0))?;
+    headers_class.define_method("inspect", method!(RbHeaders::inspect, 0))?;
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use crate::{MAX_NESTING, nesting_error};
+use magnus::r_hash::ForEach;
+use magnus::value::{Qfalse, Qtrue, ReprValue};
+use magnus::{
+    Error as MagnusError, Float, Integer, IntoValue, RArray, RHash, RString, Symbol, Value,
+    exception,
+};
+use serde_json::{Map, Number, Value as JsonValue};
+
+/// A step from the root of the value being converted, for error messages.
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+fn format_path(path: &[Segment]) -> String {
+    let mut formatted = String::from("$");
+    for segment in path {
+        match segment {
+            Segment::Key(key) => {
+                formatted.push('.');
+                formatted.push_str(key);
+            }
+            Segment::Index(index) => formatted.push_str(&format!("[{}]", index)),
+        }
+    }
+    formatted
+}
+
+fn type_error(path: &[Segment], message: String) -> MagnusError {
+    MagnusError::new(
+        exception::type_error(),
+        format!("{} (at {})", message, format_path(path)),
+    )
+}
+
+/// Serializes the `json:` option.
+pub(crate) fn generate(value: Value) -> Result<String, MagnusError> {
+    let json = to_json(value, &mut Vec::new())?;
+    Ok(json.to_string())
+}
+
+/// Converts a Ruby value to JSON without going through Ruby's `JSON` library.
+/// Hash keys are converted with `to_s`, as `JSON.generate` does.
+///
+/// Arrays and Hashes may nest [`MAX_NESTING`] deep, which `path` tracks.
+fn to_json(value: Value, path: &mut Vec<Segment>) -> Result<JsonValue, MagnusError> {
+    if value.is_nil() {
+        return Ok(JsonValue::Null);
+    }
+    if Qtrue::from_value(value).is_some() {
+        return Ok(JsonValue::Bool(true));
+    }
+    if Qfalse::from_value(value).is_some() {
+        return Ok(JsonValue::Bool(false));
+    }
+
+    if let Some(string) = RString::from_value(value) {
+        let string = string
+            .to_string()
+            .map_err(|_| type_error(path, "string is not valid UTF-8".to_string()))?;
+        return Ok(JsonValue::String(string));
+    }
+    if let Some(symbol) = Symbol::from_value(value) {
+        return Ok(JsonValue::String(symbol.name()?.into_owned()));
+    }
+
+    if let Some(integer) = Integer::from_value(value) {
+        let number = match integer.to_i64() {
+            Ok(integer) => Number::from(integer),
+            Err(_) => Number::from(
+                integer
+                    .to_u64()
+                    .map_err(|_| type_error(path, "integer is too large for JSON".to_string()))?,
+            ),
+        };
+        return Ok(JsonValue::Number(number));
+    }
+    if let Some(float) = Float::from_value(value) {
+        let number = Number::from_f64(float.to_f64()).ok_or_else(|| {
+            type_error(path, format!("{} is not allowed in JSON", float.to_f64()))
+        })?;
+        return Ok(JsonValue::Number(number));
+    }
+
+    let container = RArray::from_value(value).is_some() || RHash::from_value(value).is_some();
+    if container && path.len() >= MAX_NESTING {
+        return Err(nesting_error(&format_path(path)));
+    }
+
+    if let Some(array) = RArray::from_value(value) {
+        let mut items = Vec::with_capacity(array.len());
+        for (index, item) in array.to_vec::<Value>()?.into_iter().enumerate() {
+            path.push(Segment::Index(index));
+            items.push(to_json(item, path)?);
+            path.pop();
+        }
+        return Ok(JsonValue::Array(items));
+    }
+
+    if let Some(hash) = RHash::from_value(value) {
+        let mut map = Map::with_capacity(hash.len());
+        hash.foreach(|key: Value, item: Value| {
+            let key = key.funcall::<_, _, String>("to_s", ())?;
+            path.push(Segment::Key(key.clone()));
+            let item = to_json(item, path)?;
+            path.pop();
+            map.insert(key, item);
+            Ok(ForEach::Continue)
+        })?;
+        return Ok(JsonValue::Object(map));
+    }
+
+    Err(type_error(
+        path,
+        format!("can't convert {} to JSON", unsafe { value.classname() }),
+    ))
+}
+
+/// How [`to_ruby`] builds Ruby objects.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct DecodeOptions {
+    /// Use Symbols rather than Strings for object keys.
+    pub(crate) symbolize_keys: bool,
+    /// Freeze every String, Array and Hash built.
+    pub(crate) freeze: bool,
+}
+
+/// Converts decoded JSON into Ruby objects.
+pub(crate) fn to_ruby(json: JsonValue, options: DecodeOptions) -> Result<Value, MagnusError> {
+    let value = match json {
+        JsonValue::Null => ().into_value(),
+        JsonValue::Bool(boolean) => boolean.into_value(),
+        JsonValue::Number(number) => {
+            if let Some(integer) = number.as_i64() {
+                integer.into_value()
+            } else if let Some(integer) = number.as_u64() {
+                integer.into_value()
+            } else {
+                number.as_f64().unwrap_or(f64::NAN).into_value()
+            }
+        }
+        JsonValue::String(string) => RString::new(&string).as_value(),
+        JsonValue::Array(items) => {
+            let array = RArray::with_capacity(items.len());
+            for item in items {
+                array.push(to_ruby(item, options)?)?;
+            }
+            array.as_value()
+        }
+        JsonValue::Object(map) => {
+            let hash = RHash::new();
+            for (key, item) in map {
+                let item = to_ruby(item, options)?;
+                if options.symbolize_keys {
+                    hash.aset(Symbol::new(&key), item)?;
+                } else {
+                    hash.aset(key, item)?;
+                }
+            }
+            hash.as_value()
+        }
+    };
+
+    if options.freeze {
+        value.freeze();
+    }
+    Ok(value)
+}
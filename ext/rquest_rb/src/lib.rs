@@ -1,468 +1,717 @@
-use magnus::r_hash::ForEach;
+mod batch;
+mod body;
+mod charset;
+mod cookies;
+mod emulation;
+mod error;
+mod form;
+mod gvl;
+mod headers;
+mod json;
+mod options;
+mod parse;
+mod query;
+mod redirect;
+mod upload;
+
+use body::{BodyStream, RbBody};
+use bytes::Bytes;
+use cookies::{CookieJar, RbCookieJar};
+use emulation::{get_random_desktop_emulation, get_random_emulation, get_random_mobile_emulation};
+use error::{ErrorKind, Failure};
+use headers::RbHeaders;
+use magnus::gc::Marker;
+use magnus::scan_args::{get_kwargs, scan_args};
+use magnus::value::ReprValue;
 use magnus::{
-    Error as MagnusError, IntoValue, Module, Object, RHash, Symbol, TryConvert, Value, exception,
-    function, method,
+    DataTypeFunctions, Error as MagnusError, IntoValue, KwArgs, Module, Object, RArray, RHash,
+    RString, Symbol, TryConvert, Value, exception, function, method,
+};
+use options::{RequestBody, RequestOptions, RequestOverrides};
+use query::ArrayStyle;
+use redirect::{Hop, Redirects};
+use rquest::header::{
+    ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, HeaderValue, TRANSFER_ENCODING,
 };
 use rquest::redirect::Policy;
-use rquest::{Error as RquestError, Response as RquestResponse};
+use rquest::{Error as RquestError, Method, Request, RequestBuilder, Response as RquestResponse};
 use rquest_util::Emulation as RquestEmulation;
-use std::cell::{Cell, RefCell};
-use std::collections::HashMap;
-use std::collections::hash_map::RandomState;
-use std::hash::{BuildHasher, Hasher};
-use std::num::Wrapping;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
-
-// Fast random implementation similar to rquest-util crate
-fn fast_random() -> u64 {
-    thread_local! {
-        static RNG: Cell<Wrapping<u64>> = Cell::new(Wrapping(seed()));
-    }
-
-    #[inline]
-    fn seed() -> u64 {
-        let seed = RandomState::new();
-        let mut out = 0;
-        let mut cnt = 0;
-        while out == 0 {
-            cnt += 1;
-            let mut hasher = seed.build_hasher();
-            hasher.write_usize(cnt);
-            out = hasher.finish();
+use upload::Feeder;
+
+/// Returns the process-wide runtime.
+///
+/// Clients are shared between Ruby threads, and their pooled connections are
+/// driven by tasks on the runtime that opened them, so a runtime per thread
+/// would kill those connections as soon as the thread exits. The runtime is
+/// recreated after a fork because its worker threads don't survive it.
+fn get_runtime() -> Arc<Runtime> {
+    static RUNTIME: Mutex<Option<(u32, Arc<Runtime>)>> = Mutex::new(None);
+
+    let pid = std::process::id();
+    let mut runtime = RUNTIME.lock().unwrap_or_else(|e| e.into_inner());
+    match runtime.as_ref() {
+        Some((owner, rt)) if *owner == pid => rt.clone(),
+        _ => {
+            let rt = Arc::new(Runtime::new().expect("Failed to create runtime"));
+            // Dropping a runtime inherited from the parent would try to join
+            // worker threads that only exist there.
+            if let Some(stale) = runtime.replace((pid, rt.clone())) {
+                std::mem::forget(stale);
+            }
+            rt
         }
-        out
     }
-
-    RNG.with(|rng| {
-        let mut n = rng.get();
-        debug_assert_ne!(n.0, 0);
-        n ^= n >> 12;
-        n ^= n << 25;
-        n ^= n >> 27;
-        rng.set(n);
-        n.0.wrapping_mul(0x2545f4914f6cdd1d)
-    })
 }
 
-fn get_random_desktop_emulation() -> RquestEmulation {
-    let browsers = [
-        RquestEmulation::Chrome134,
-        RquestEmulation::Chrome128,
-        RquestEmulation::Chrome101,
-        RquestEmulation::Firefox135,
-        RquestEmulation::Safari17_0,
-    ];
+const JSON_MIME_TYPE: &str = "application/json";
+
+/// Parses an HTTP verb given as a Symbol or String, such as `:get` or `"PATCH"`.
+///
+/// Symbols are uppercased, as http.rb does, and so are Strings naming standard
+/// methods in any case. Method names are case-sensitive, so other Strings are
+/// sent exactly as given.
+fn parse_method(verb: Value) -> Result<Method, MagnusError> {
+    let name = match Symbol::from_value(verb) {
+        Some(symbol) => symbol.name()?.to_uppercase(),
+        None => {
+            let name = String::try_convert(verb)?;
+            let upper = name.to_uppercase();
+            match upper.as_str() {
+                "GET" | "HEAD" | "POST" | "PUT" | "DELETE" | "CONNECT" | "OPTIONS" | "TRACE"
+                | "PATCH" => upper,
+                _ => name,
+            }
+        }
+    };
 
-    let index = (fast_random() as usize) % browsers.len();
-    browsers[index]
+    Method::from_bytes(name.as_bytes()).map_err(|_| {
+        MagnusError::new(
+            exception::arg_error(),
+            format!("invalid HTTP method: {}", name),
+        )
+    })
 }
 
-fn get_random_mobile_emulation() -> RquestEmulation {
-    let browsers = [
-        RquestEmulation::SafariIos17_4_1,
-        RquestEmulation::SafariIos17_2,
-        RquestEmulation::SafariIos16_5,
-        RquestEmulation::FirefoxAndroid135,
-    ];
-
-    let index = (fast_random() as usize) % browsers.len();
-    browsers[index]
-}
+/// An rquest client shared by every `RbHttpClient` derived from the one that
+/// built it, so they all use the same connection pool.
+#[derive(Clone)]
+#[magnus::wrap(class = "Rquest::HTTP::Client")]
+struct ClientWrap(Arc<rquest::Client>);
 
-fn get_random_emulation() -> RquestEmulation {
-    if fast_random() % 100 < 50 {
-        get_random_desktop_emulation()
-    } else {
-        get_random_mobile_emulation()
+impl ClientWrap {
+    fn inner(&self) -> &rquest::Client {
+        &self.0
     }
 }
 
-fn rquest_error_to_magnus_error(err: RquestError) -> MagnusError {
-    MagnusError::new(
-        exception::runtime_error(),
-        format!("HTTP request failed: {}", err),
-    )
+/// Timeouts set through `Client#timeout`, mirroring http.rb's global and
+/// per-operation modes.
+#[derive(Clone, Copy, Default)]
+struct Timeouts {
+    total: Option<Duration>,
+    connect: Option<Duration>,
+    read: Option<Duration>,
 }
 
-fn get_runtime() -> Arc<Runtime> {
-    thread_local! {
-        static RUNTIME: RefCell<Option<Arc<Runtime>>> = RefCell::new(None);
-    }
-
-    RUNTIME.with(|cell| {
-        let mut runtime = cell.borrow_mut();
-        if runtime.is_none() {
-            *runtime = Some(Arc::new(Runtime::new().expect("Failed to create runtime")));
+impl Timeouts {
+    /// Parses a number of seconds for the whole request, or a Hash of
+    /// `connect:`, `read:` and `write:` timeouts.
+    ///
+    /// `write:` is accepted so http.rb code keeps working, but has no effect:
+    /// rquest can't tell when a request has been sent, so it could only bound
+    /// the wait for the response.
+    fn from_value(options: Value) -> Result<Self, MagnusError> {
+        let mut timeouts = Timeouts::default();
+
+        if let Some(options) = RHash::from_value(options) {
+            let kwargs = get_kwargs::<_, (), (Option<f64>, Option<f64>, Option<f64>), ()>(
+                options,
+                &[],
+                &["connect", "read", "write"],
+            )?;
+            let (connect, read, write) = kwargs.optional;
+            // Still validated, so a bad value fails here as it does in http.rb.
+            write.map(seconds_to_duration).transpose()?;
+            timeouts.connect = connect.map(seconds_to_duration).transpose()?;
+            timeouts.read = read.map(seconds_to_duration).transpose()?;
+        } else {
+            timeouts.total = Some(seconds_to_duration(f64::try_convert(options)?)?);
         }
-        runtime.as_ref().unwrap().clone()
-    })
-}
 
-fn extract_body(args: &[Value]) -> Result<Option<String>, MagnusError> {
-    if args.len() <= 1 {
-        return Ok(None);
+        Ok(timeouts)
     }
 
-    let body_value = &args[1];
-    if let Ok(body_hash) = RHash::try_convert(*body_value) {
-        let body_key = Symbol::new("body").into_value();
-        if let Some(body) = body_hash.get(body_key) {
-            if let Ok(body_str) = String::try_convert(body) {
-                return Ok(Some(body_str));
-            }
+    fn apply(&self, mut req: RequestBuilder) -> RequestBuilder {
+        if let Some(total) = self.total {
+            req = req.timeout(total);
+        }
+        if let Some(read) = self.read {
+            req = req.read_timeout(read);
         }
-        Ok(None)
-    } else {
-        Ok(Some(String::try_convert(*body_value)?))
+        req
     }
-}
 
-#[magnus::wrap(class = "Rquest::HTTP::Client")]
-struct ClientWrap(rquest::Client);
-
-impl ClientWrap {
-    fn inner(&self) -> &rquest::Client {
-        &self.0
+    /// Narrows a timeout reported by rquest down to the deadline that
+    /// expired, going by how long the request took.
+    ///
+    /// rquest reports read and total timeouts the same way. The total deadline
+    /// wins once it has passed; a read timeout can't fire before `read` has
+    /// elapsed either, so anything sooner stays a plain timeout.
+    fn refine(&self, kind: ErrorKind, elapsed: Duration) -> ErrorKind {
+        if kind != ErrorKind::Timeout {
+            return kind;
+        }
+        match (self.total, self.read) {
+            (Some(total), _) if elapsed >= total => ErrorKind::Timeout,
+            (_, Some(read)) if elapsed >= read => ErrorKind::ReadTimeout,
+            _ => kind,
+        }
     }
 }
 
-impl Clone for ClientWrap {
-    fn clone(&self) -> Self {
-        // This creates a new client with the same settings
-        ClientWrap(
-            rquest::Client::builder()
-                .emulation(get_random_emulation())
-                .build()
-                .expect("Failed to create client"),
+fn seconds_to_duration(seconds: f64) -> Result<Duration, MagnusError> {
+    Duration::try_from_secs_f64(seconds).map_err(|_| {
+        MagnusError::new(
+            exception::arg_error(),
+            format!("invalid timeout: {}", seconds),
         )
-    }
+    })
 }
 
-#[magnus::wrap(class = "Rquest::HTTP::Client")]
+/// How deeply `json:`, `form:` and `params:` values may nest, as with
+/// `JSON.generate`'s default `max_nesting`. Values that contain themselves hit
+/// the limit instead of overflowing the stack.
+const MAX_NESTING: usize = 100;
+
+/// The error for a value nested deeper than [`MAX_NESTING`], at the key path
+/// `at`.
+fn nesting_error(at: &str) -> MagnusError {
+    MagnusError::new(
+        exception::arg_error(),
+        format!("nesting of {} is too deep (at {})", MAX_NESTING + 1, at),
+    )
+}
+
+#[magnus::wrap(class = "Rquest::HTTP::Client", mark)]
 struct RbHttpClient {
     client: ClientWrap,
-    default_headers: HashMap<String, String>,
-    follow_redirects: bool,
+    emulation: RquestEmulation,
+    default_headers: HeaderMap,
+    redirects: Redirects,
     proxy: Option<String>,
-    timeout: Option<Duration>,
+    timeouts: Timeouts,
+    stream: bool,
+    params_style: ArrayStyle,
+    cookie_jar: Option<Arc<CookieJar>>,
+    /// Per-request `proxy:` and `emulation:`, set by `with_options`.
+    overrides: RequestOverrides,
 }
 
 impl RbHttpClient {
     fn new() -> Self {
-        Self {
-            client: ClientWrap(
-                rquest::Client::builder()
-                    .emulation(get_random_emulation())
-                    .build()
-                    .expect("Failed to create client"),
-            ),
-            default_headers: HashMap::new(),
-            follow_redirects: true,
-            proxy: None,
-            timeout: None,
-        }
+        Self::with_emulation(get_random_emulation())
     }
 
     fn new_desktop() -> Self {
-        Self {
-            client: ClientWrap(
-                rquest::Client::builder()
-                    .emulation(get_random_desktop_emulation())
-                    .build()
-                    .expect("Failed to create client"),
-            ),
-            default_headers: HashMap::new(),
-            follow_redirects: true,
-            proxy: None,
-            timeout: None,
-        }
+        Self::with_emulation(get_random_desktop_emulation())
     }
 
     fn new_mobile() -> Self {
+        Self::with_emulation(get_random_mobile_emulation())
+    }
+
+    /// The process-wide client behind the `Rquest::HTTP` module functions, so
+    /// one-shot calls reuse pooled connections. Like the runtime, it is rebuilt
+    /// after a fork so parent and child never share sockets.
+    fn shared() -> Self {
+        static DEFAULT_CLIENT: Mutex<Option<(u32, RbHttpClient)>> = Mutex::new(None);
+
+        let pid = std::process::id();
+        let mut default = DEFAULT_CLIENT.lock().unwrap_or_else(|e| e.into_inner());
+        match default.as_ref() {
+            Some((owner, client)) if *owner == pid => client.clone(),
+            _ => {
+                let client = Self::new();
+                *default = Some((pid, client.clone()));
+                client
+            }
+        }
+    }
+
+    fn with_emulation(emulation: RquestEmulation) -> Self {
         Self {
-            client: ClientWrap(
+            client: ClientWrap(Arc::new(
                 rquest::Client::builder()
-                    .emulation(get_random_mobile_emulation())
+                    .emulation(emulation)
+                    .redirect(Policy::none())
                     .build()
                     .expect("Failed to create client"),
-            ),
-            default_headers: HashMap::new(),
-            follow_redirects: true,
+            )),
+            emulation,
+            default_headers: HeaderMap::new(),
+            redirects: Redirects::default(),
             proxy: None,
-            timeout: None,
+            timeouts: Timeouts::default(),
+            stream: false,
+            params_style: ArrayStyle::default(),
+            cookie_jar: None,
+            overrides: RequestOverrides::default(),
         }
     }
 
-    fn with_headers(&self, headers: HashMap<String, String>) -> Self {
-        let mut new_client = self.clone();
-        new_client.default_headers.clear();
-
-        for (name, value) in headers {
-            new_client
-                .default_headers
-                .insert(name.to_lowercase(), value);
+    /// `Client.new` or `Client.new(emulation: :chrome134)`.
+    fn rb_new(args: &[Value]) -> Result<Self, MagnusError> {
+        let args = scan_args::<(), (Option<RHash>,), (), (), (), ()>(args)?;
+        let (options,) = args.optional;
+        let Some(options) = options else {
+            return Ok(Self::new());
+        };
+
+        let kwargs = get_kwargs::<_, (), (Option<Value>,), ()>(options, &[], &["emulation"])?;
+        match kwargs.optional.0 {
+            Some(name) => Ok(Self::with_emulation(emulation::from_value(name)?)),
+            None => Ok(Self::new()),
         }
-        new_client
     }
 
-    fn with_proxy(&self, proxy: String) -> Self {
+    /// Merges headers into the client's, replacing the values of fields that
+    /// were already set: `headers(accept: "text/html", user_agent: "App/1.0")`.
+    fn with_headers(&self, headers: Value) -> Result<Self, MagnusError> {
         let mut new_client = self.clone();
-        new_client.proxy = Some(proxy.clone());
-
-        new_client.client = ClientWrap(
-            rquest::Client::builder()
-                .emulation(get_random_emulation())
-                .proxy(proxy)
-                .build()
-                .expect("Failed to create client with proxy"),
+        headers::merge(
+            &mut new_client.default_headers,
+            &headers::header_map(headers)?,
         );
-
-        new_client
-    }
-
-    fn follow(&self, follow: bool) -> Self {
-        let mut new_client = self.clone();
-        new_client.follow_redirects = follow;
-        new_client
+        Ok(new_client)
     }
 
-    fn get(&self, url: String) -> Result<RbHttpResponse, MagnusError> {
-        let rt = get_runtime();
-        let mut req = self.client.inner().get(&url);
-
-        for (name, value) in &self.default_headers {
-            req = req.header(name, value);
-        }
-
-        if !self.default_headers.contains_key("accept") {
-            req = req.header("Accept", "application/json");
-        }
-
-        if let Some(user_agent) = self.default_headers.get("user-agent") {
-            req = req.header("User-Agent", user_agent);
+    /// Builds the underlying rquest client from the client-level settings.
+    ///
+    /// Only settings that live on the rquest client (emulation, proxy, connect
+    /// timeout and cookie jar) need a rebuild; everything else is applied per
+    /// request.
+    fn build_client(&self) -> ClientWrap {
+        // Redirects are followed by `send`, one hop at a time.
+        let mut builder = rquest::Client::builder()
+            .emulation(self.emulation)
+            .redirect(Policy::none());
+
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.clone());
         }
 
-        if self.follow_redirects {
-            req = req.redirect(Policy::limited(10));
-        } else {
-            req = req.redirect(Policy::none());
+        if let Some(connect) = self.timeouts.connect {
+            builder = builder.connect_timeout(connect);
         }
 
-        if let Some(timeout) = self.timeout {
-            req = req.timeout(timeout);
+        if let Some(jar) = &self.cookie_jar {
+            builder = builder.cookie_provider(jar.clone());
         }
 
-        match rt.block_on(req.send()) {
-            Ok(response) => Ok(RbHttpResponse::new(response)),
-            Err(e) => Err(rquest_error_to_magnus_error(e)),
-        }
+        ClientWrap(Arc::new(builder.build().expect("Failed to create client")))
     }
 
-    fn post(&self, args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
-        let url = String::try_convert(args[0])?;
-        let body = extract_body(args)?;
-
-        let rt = get_runtime();
-        let mut req = self.client.inner().post(&url);
+    fn emulation(&self, name: Value) -> Result<Self, MagnusError> {
+        let mut new_client = self.clone();
+        new_client.emulation = emulation::from_value(name)?;
+        new_client.client = new_client.build_client();
+        Ok(new_client)
+    }
 
-        for (name, value) in &self.default_headers {
-            req = req.header(name, value);
-        }
+    fn with_proxy(&self, proxy: String) -> Self {
+        let mut new_client = self.clone();
+        new_client.proxy = Some(proxy);
+        new_client.client = new_client.build_client();
+        new_client
+    }
 
-        if !self.default_headers.contains_key("accept") {
-            req = req.header("Accept", "application/json");
-        }
-        if !self.default_headers.contains_key("content-type") {
-            req = req.header("Content-Type", "application/json");
-        }
+    /// `timeout(10)` sets a timeout for the whole request, while
+    /// `timeout(connect: 2, read: 5, write: 5)` sets per-phase timeouts.
+    fn timeout(&self, options: Value) -> Result<Self, MagnusError> {
+        let timeouts = Timeouts::from_value(options)?;
 
-        if let Some(user_agent) = self.default_headers.get("user-agent") {
-            req = req.header("User-Agent", user_agent);
+        let mut new_client = self.clone();
+        new_client.timeouts = timeouts;
+        if timeouts.connect != self.timeouts.connect {
+            new_client.client = new_client.build_client();
         }
+        Ok(new_client)
+    }
 
-        if self.follow_redirects {
-            req = req.redirect(Policy::limited(10));
-        } else {
-            req = req.redirect(Policy::none());
-        }
+    /// `follow`, `follow(false)`, or `follow(max_hops: 5, strict: true,
+    /// on_redirect: ->(from, to, status) { ... })`.
+    fn follow(&self, args: &[Value]) -> Result<Self, MagnusError> {
+        let mut new_client = self.clone();
+        new_client.redirects = Redirects::from_args(args)?;
+        Ok(new_client)
+    }
 
-        if let Some(timeout) = self.timeout {
-            req = req.timeout(timeout);
-        }
+    /// Sets how arrays in `params:` are written: `:repeat` (`tags=x&tags=y`,
+    /// the default), `:brackets` (`tags[]=x&tags[]=y`) or `:comma` (`tags=x,y`).
+    fn params_style(&self, style: Value) -> Result<Self, MagnusError> {
+        let mut new_client = self.clone();
+        new_client.params_style = ArrayStyle::from_value(style)?;
+        Ok(new_client)
+    }
 
-        if let Some(body) = body {
-            req = req.body(body);
-        }
+    /// Stores and sends cookies with `jar`, or with a new jar when none is
+    /// given: `cookies` or `cookies(jar)`.
+    fn cookies(&self, args: &[Value]) -> Result<Self, MagnusError> {
+        let args = scan_args::<(), (Option<&RbCookieJar>,), (), (), (), ()>(args)?;
+        let jar = match args.optional.0 {
+            Some(jar) => jar.jar(),
+            None => Arc::default(),
+        };
 
-        match rt.block_on(req.send()) {
-            Ok(response) => Ok(RbHttpResponse::new(response)),
-            Err(e) => Err(rquest_error_to_magnus_error(e)),
-        }
+        let mut new_client = self.clone();
+        new_client.cookie_jar = Some(jar);
+        new_client.client = new_client.build_client();
+        Ok(new_client)
     }
 
-    fn put(&self, args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
-        let url = String::try_convert(args[0])?;
-        let body = extract_body(args)?;
+    fn cookie_jar(&self) -> Option<RbCookieJar> {
+        self.cookie_jar.clone().map(RbCookieJar::new)
+    }
 
-        let rt = get_runtime();
-        let mut req = self.client.inner().put(&url);
+    /// Sets the `Accept` header, like http.rb: `accept(:json)` or
+    /// `accept("text/html")`.
+    fn accept(&self, mime_type: Value) -> Result<Self, MagnusError> {
+        let mime_type = match Symbol::from_value(mime_type) {
+            Some(symbol) if symbol.name()? == "json" => JSON_MIME_TYPE.to_string(),
+            Some(symbol) => {
+                return Err(MagnusError::new(
+                    exception::arg_error(),
+                    format!("unknown MIME type shortcut: {}", symbol.name()?),
+                ));
+            }
+            None => String::try_convert(mime_type)?,
+        };
+
+        let value = HeaderValue::from_str(&mime_type).map_err(|_| {
+            MagnusError::new(
+                exception::arg_error(),
+                format!("invalid Accept value: {:?}", mime_type),
+            )
+        })?;
+        let mut new_client = self.clone();
+        new_client.default_headers.insert(ACCEPT, value);
+        Ok(new_client)
+    }
 
-        for (name, value) in &self.default_headers {
-            req = req.header(name, value);
-        }
+    /// Makes responses stream their bodies: `Response#body` returns a `Body`
+    /// that reads from the connection as it is consumed.
+    fn streaming(&self) -> Self {
+        let mut new_client = self.clone();
+        new_client.stream = true;
+        new_client
+    }
 
-        if !self.default_headers.contains_key("accept") {
-            req = req.header("Accept", "application/json");
-        }
-        if !self.default_headers.contains_key("content-type") {
-            req = req.header("Content-Type", "application/json");
+    /// Applies the client's default headers and redirect policy to a new request
+    /// and attaches its body, along with the feeders for any parts streamed
+    /// from Ruby.
+    fn prepare(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<RequestBody>,
+    ) -> Result<(RequestBuilder, Vec<Feeder>), MagnusError> {
+        // Other headers are left to the emulated browser's defaults, which are
+        // part of its fingerprint.
+        let is_json = matches!(body, Some(RequestBody::Json(_)));
+        let mut req = self.client.inner().request(method, url);
+
+        req = req.headers(self.default_headers.clone());
+
+        if is_json && !self.default_headers.contains_key(CONTENT_TYPE) {
+            req = req.header(CONTENT_TYPE, JSON_MIME_TYPE);
         }
 
-        if let Some(user_agent) = self.default_headers.get("user-agent") {
-            req = req.header("User-Agent", user_agent);
-        }
+        req = self.overrides.apply(req.redirect(Policy::none()));
 
-        if self.follow_redirects {
-            req = req.redirect(Policy::limited(10));
-        } else {
-            req = req.redirect(Policy::none());
+        match body {
+            Some(RequestBody::Text(body) | RequestBody::Json(body)) => {
+                Ok((req.body(body), Vec::new()))
+            }
+            Some(RequestBody::Upload(upload)) => {
+                let (req, feeder) = upload.attach(req);
+                Ok((req, vec![feeder]))
+            }
+            Some(RequestBody::Form(form)) => form.attach(req),
+            None => Ok((req, Vec::new())),
         }
+    }
 
-        if let Some(timeout) = self.timeout {
-            req = req.timeout(timeout);
-        }
+    /// Sends a request and waits for the response headers, following
+    /// redirects. Returns the final response along with the redirects that led
+    /// to it.
+    ///
+    /// Runs without the GVL, which is only reacquired to call `on_redirect`.
+    async fn send(
+        &self,
+        req: RequestBuilder,
+        started: Instant,
+    ) -> Result<(RquestResponse, Vec<Hop>), Failure> {
+        let mut request = self
+            .timeouts
+            .apply(req)
+            .build()
+            .map_err(|e| self.failure(e, started))?;
+        let mut history = Vec::new();
+
+        loop {
+            let method = request.method().clone();
+            let headers = request.headers().clone();
+            // Streamed bodies can't be cloned, and so can't be sent again.
+            let retry = request.try_clone();
+
+            let response = self.send_once(request, started).await?;
+            let redirect = match self.redirects.next(&response, &method) {
+                Ok(Some(redirect)) => redirect,
+                Ok(None) => return Ok((response, history)),
+                Err(message) => {
+                    return Err(Failure::Redirect(ErrorKind::Redirect, message, history));
+                }
+            };
+
+            let location = redirect.hop.location.clone();
+            history.push(redirect.hop);
+            if history.len() > self.redirects.max_hops {
+                return Err(Failure::Redirect(
+                    ErrorKind::TooManyRedirects,
+                    format!("too many redirects (max_hops: {})", self.redirects.max_hops),
+                    history,
+                ));
+            }
+            if self.redirects.has_callback() {
+                let hop = history.last().expect("a hop was just recorded");
+                gvl::with_gvl(|| self.redirects.notify(hop)).map_err(Failure::Ruby)?;
+            }
 
-        if let Some(body) = body {
-            req = req.body(body);
-        }
+            request = match retry {
+                Some(mut retry) => {
+                    *retry.url_mut() = location;
+                    retry
+                }
+                None if !redirect.keep_body => self
+                    .timeouts
+                    .apply(
+                        self.overrides.apply(
+                            self.client
+                                .inner()
+                                .request(redirect.method.clone(), location),
+                        ),
+                    )
+                    .headers(headers)
+                    .redirect(Policy::none())
+                    .build()
+                    .map_err(|e| self.failure(e, started))?,
+                None => {
+                    let status = history.last().map_or(0, |hop| hop.status);
+                    return Err(Failure::Redirect(
+                        ErrorKind::Redirect,
+                        format!("can't send a streamed body again for a {} redirect", status),
+                        history,
+                    ));
+                }
+            };
 
-        match rt.block_on(req.send()) {
-            Ok(response) => Ok(RbHttpResponse::new(response)),
-            Err(e) => Err(rquest_error_to_magnus_error(e)),
+            *request.method_mut() = redirect.method;
+            if !redirect.keep_body {
+                *request.body_mut() = None;
+                for name in [CONTENT_TYPE, CONTENT_LENGTH, TRANSFER_ENCODING] {
+                    request.headers_mut().remove(name);
+                }
+            }
+            // Credentials set with `.headers` don't follow a redirect to
+            // another origin.
+            let hop = history.last().expect("a hop was just recorded");
+            self.redirects
+                .strip(&hop.url, &hop.location, request.headers_mut());
+            // The total timeout covers the whole redirect chain.
+            if let Some(total) = self.timeouts.total {
+                *request.timeout_mut() = Some(total.saturating_sub(started.elapsed()));
+            }
         }
     }
 
-    fn delete(&self, url: String) -> Result<RbHttpResponse, MagnusError> {
-        let rt = get_runtime();
-        let mut req = self.client.inner().delete(&url);
+    /// Sends one request, without following redirects.
+    async fn send_once(
+        &self,
+        request: Request,
+        started: Instant,
+    ) -> Result<RquestResponse, Failure> {
+        self.client
+            .inner()
+            .execute(request)
+            .await
+            .map_err(|e| self.failure(e, started))
+    }
 
-        for (name, value) in &self.default_headers {
-            req = req.header(name, value);
-        }
+    /// Sends a request and reads its whole response. Runs without the GVL, so
+    /// it must not touch Ruby objects.
+    async fn perform(&self, req: RequestBuilder) -> Result<ResponseData, Failure> {
+        let started = Instant::now();
+        let (response, history) = self.send(req, started).await?;
 
-        if !self.default_headers.contains_key("accept") {
-            req = req.header("Accept", "application/json");
-        }
+        // Failing to read the body is an error too, rather than an empty body.
+        ResponseData::read(response, history)
+            .await
+            .map_err(|e| self.failure(e, started))
+    }
 
-        if let Some(user_agent) = self.default_headers.get("user-agent") {
-            req = req.header("User-Agent", user_agent);
-        }
+    fn failure(&self, err: RquestError, started: Instant) -> Failure {
+        let kind = self
+            .timeouts
+            .refine(error::classify(&err), started.elapsed());
+        Failure::Rquest(kind, err)
+    }
 
-        if self.follow_redirects {
-            req = req.redirect(Policy::limited(10));
-        } else {
-            req = req.redirect(Policy::none());
+    /// A copy of this client with a request's own options applied. It shares
+    /// the rquest client, so no new connection pool is created.
+    fn with_options(&self, options: &RequestOptions) -> Self {
+        let mut client = self.clone();
+        if let Some(headers) = &options.headers {
+            headers::merge(&mut client.default_headers, headers);
         }
-
-        if let Some(timeout) = self.timeout {
-            req = req.timeout(timeout);
+        if let Some(timeouts) = options.timeouts {
+            client.timeouts = timeouts;
         }
-
-        match rt.block_on(req.send()) {
-            Ok(response) => Ok(RbHttpResponse::new(response)),
-            Err(e) => Err(rquest_error_to_magnus_error(e)),
+        if let Some(redirects) = &options.redirects {
+            client.redirects = redirects.clone();
         }
+        client.overrides = options.overrides.clone();
+        client
     }
 
-    fn head(&self, url: String) -> Result<RbHttpResponse, MagnusError> {
-        let rt = get_runtime();
-        let mut req = self.client.inner().head(&url);
-
-        for (name, value) in &self.default_headers {
-            req = req.header(name, value);
-        }
-
-        if !self.default_headers.contains_key("accept") {
-            req = req.header("Accept", "application/json");
-        }
+    /// Builds a request from `url` and its options, ready to send.
+    fn prepare_request(
+        &self,
+        method: Method,
+        url: String,
+        mut options: RequestOptions,
+    ) -> Result<PreparedRequest, MagnusError> {
+        let client = self.with_options(&options);
+        let url = match options.params {
+            Some(params) => query::merge(&url, params, client.params_style)?,
+            None => url,
+        };
+
+        let (req, feeders) = client.prepare(method.clone(), &url, options.body.take())?;
+
+        Ok(PreparedRequest {
+            client,
+            method,
+            url,
+            req,
+            feeders,
+        })
+    }
 
-        if let Some(user_agent) = self.default_headers.get("user-agent") {
-            req = req.header("User-Agent", user_agent);
+    /// Sends `(url, options)` and returns its response.
+    fn execute(&self, method: Method, args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+        let (url, options) = RequestOptions::parse(args)?;
+        let PreparedRequest {
+            client,
+            method,
+            url,
+            req,
+            feeders,
+        } = self.prepare_request(method, url, options)?;
+
+        if !client.stream {
+            return match gvl::block_on(upload::feeding(client.perform(req), feeders))?? {
+                Ok(data) => Ok(RbHttpResponse::new(data)),
+                Err(failure) => Err(failure.into_error(method.as_str(), &url)),
+            };
         }
 
-        if self.follow_redirects {
-            req = req.redirect(Policy::limited(10));
-        } else {
-            req = req.redirect(Policy::none());
+        let started = Instant::now();
+        match gvl::block_on(upload::feeding(client.send(req, started), feeders))?? {
+            Ok((response, history)) => Ok(RbHttpResponse::new(ResponseData::stream(
+                response,
+                history,
+                method,
+                &url,
+                client.timeouts,
+                started,
+            ))),
+            Err(failure) => Err(failure.into_error(method.as_str(), &url)),
         }
+    }
 
-        if let Some(timeout) = self.timeout {
-            req = req.timeout(timeout);
-        }
+    /// `request(verb, url, **options)`, for any method token: `:options`,
+    /// `:trace`, or extension methods such as `"PROPFIND"`.
+    fn request(&self, args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+        let Some((verb, args)) = args.split_first() else {
+            return Err(MagnusError::new(
+                exception::arg_error(),
+                "wrong number of arguments (given 0, expected 2..3)",
+            ));
+        };
+        self.execute(parse_method(*verb)?, args)
+    }
 
-        match rt.block_on(req.send()) {
-            Ok(response) => Ok(RbHttpResponse::new(response)),
-            Err(e) => Err(rquest_error_to_magnus_error(e)),
-        }
+    fn get(&self, args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+        self.execute(Method::GET, args)
     }
 
-    fn patch(&self, args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
-        let url = String::try_convert(args[0])?;
-        let body = extract_body(args)?;
+    fn post(&self, args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+        self.execute(Method::POST, args)
+    }
 
-        let rt = get_runtime();
-        let mut req = self.client.inner().patch(&url);
+    fn put(&self, args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+        self.execute(Method::PUT, args)
+    }
 
-        for (name, value) in &self.default_headers {
-            req = req.header(name, value);
-        }
+    fn delete(&self, args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+        self.execute(Method::DELETE, args)
+    }
 
-        if !self.default_headers.contains_key("accept") {
-            req = req.header("Accept", "application/json");
-        }
-        if !self.default_headers.contains_key("content-type") {
-            req = req.header("Content-Type", "application/json");
-        }
+    fn head(&self, args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+        self.execute(Method::HEAD, args)
+    }
 
-        if let Some(user_agent) = self.default_headers.get("user-agent") {
-            req = req.header("User-Agent", user_agent);
-        }
+    fn patch(&self, args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+        self.execute(Method::PATCH, args)
+    }
 
-        if self.follow_redirects {
-            req = req.redirect(Policy::limited(10));
-        } else {
-            req = req.redirect(Policy::none());
-        }
+    fn options(&self, args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+        self.execute(Method::OPTIONS, args)
+    }
 
-        if let Some(timeout) = self.timeout {
-            req = req.timeout(timeout);
-        }
+    fn trace(&self, args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+        self.execute(Method::TRACE, args)
+    }
 
-        if let Some(body) = body {
-            req = req.body(body);
-        }
+    fn parallel(&self, args: &[Value]) -> Result<RArray, MagnusError> {
+        batch::parallel(self, args)
+    }
 
-        match rt.block_on(req.send()) {
-            Ok(response) => Ok(RbHttpResponse::new(response)),
-            Err(e) => Err(rquest_error_to_magnus_error(e)),
-        }
+    fn each_completed(&self, args: &[Value]) -> Result<(), MagnusError> {
+        batch::each_completed(self, args)
     }
 
-    fn headers(&self, headers_hash: RHash) -> Self {
-        let mut headers = HashMap::new();
+    fn headers(&self, headers: Value) -> Result<Self, MagnusError> {
+        self.with_headers(headers)
+    }
+}
 
-        let _ = headers_hash.foreach(|key: Value, value: Value| {
-            if let (Ok(key_str), Ok(value_str)) =
-                (String::try_convert(key), String::try_convert(value))
-            {
-                headers.insert(key_str.to_lowercase(), value_str);
-            }
-            Ok(ForEach::Continue)
-        });
+/// A request built by [`RbHttpClient::prepare_request`], with the client
+/// settings that apply to it.
+struct PreparedRequest {
+    client: RbHttpClient,
+    method: Method,
+    url: String,
+    req: RequestBuilder,
+    feeders: Vec<Feeder>,
+}
 
-        self.with_headers(headers)
+impl DataTypeFunctions for RbHttpClient {
+    fn mark(&self, marker: &Marker) {
+        self.redirects.mark(marker);
     }
 }
 
@@ -470,19 +719,30 @@ impl Clone for RbHttpClient {
     fn clone(&self) -> Self {
         Self {
             client: self.client.clone(),
+            emulation: self.emulation,
             default_headers: self.default_headers.clone(),
-            follow_redirects: self.follow_redirects,
+            redirects: self.redirects.clone(),
             proxy: self.proxy.clone(),
-            timeout: self.timeout,
+            timeouts: self.timeouts,
+            stream: self.stream,
+            params_style: self.params_style,
+            cookie_jar: self.cookie_jar.clone(),
+            overrides: self.overrides.clone(),
         }
     }
 }
 
 struct ResponseData {
     status: u16,
-    headers: HashMap<String, String>,
-    body: Option<String>,
+    headers: HeaderMap,
+    body: ResponseBody,
     url: String,
+    history: Vec<Hop>,
+}
+
+enum ResponseBody {
+    Buffered(Bytes),
+    Streaming(Arc<BodyStream>),
 }
 
 #[magnus::wrap(class = "Rquest::HTTP::Response")]
@@ -490,34 +750,56 @@ struct RbHttpResponse {
     data: Arc<ResponseData>,
 }
 
-impl RbHttpResponse {
-    fn new(response: RquestResponse) -> Self {
-        let rt = get_runtime();
+impl ResponseData {
+    fn new(response: &RquestResponse, history: Vec<Hop>, body: ResponseBody) -> Self {
+        Self {
+            status: response.status().as_u16(),
+            headers: response.headers().clone(),
+            body,
+            url: response.url().to_string(),
+            history,
+        }
+    }
 
-        let status = response.status().as_u16();
-        let url = response.url().to_string();
+    async fn read(response: RquestResponse, history: Vec<Hop>) -> Result<Self, RquestError> {
+        let mut data = Self::new(&response, history, ResponseBody::Buffered(Bytes::new()));
+        data.body = ResponseBody::Buffered(response.bytes().await?);
+        Ok(data)
+    }
 
-        let mut headers = HashMap::new();
-        for (name, value) in response.headers().iter() {
-            if let Ok(value_str) = value.to_str() {
-                headers.insert(name.to_string(), value_str.to_string());
-            }
-        }
+    /// Leaves the body on the connection, to be read through `Response#body`.
+    fn stream(
+        response: RquestResponse,
+        history: Vec<Hop>,
+        method: Method,
+        url: &str,
+        timeouts: Timeouts,
+        started: Instant,
+    ) -> Self {
+        let mut data = Self::new(&response, history, ResponseBody::Buffered(Bytes::new()));
+        data.body = ResponseBody::Streaming(Arc::new(BodyStream::new(
+            response,
+            method,
+            url.to_string(),
+            timeouts,
+            started,
+        )));
+        data
+    }
 
-        let body = rt.block_on(async {
-            match response.text().await {
-                Ok(text) => Some(text),
-                Err(_) => None,
-            }
-        });
+    /// The whole body, reading the rest of a streamed one.
+    fn contents(&self) -> Result<Bytes, MagnusError> {
+        match &self.body {
+            ResponseBody::Buffered(body) => Ok(body.clone()),
+            ResponseBody::Streaming(stream) => stream.read_to_end(),
+        }
+    }
+}
 
+impl RbHttpResponse {
+    fn new(data: ResponseData) -> Self {
         Self {
-            data: Arc::new(ResponseData {
-                status,
-                headers,
-                body,
-                url,
-            }),
+            data: Arc::new(data),
         }
     }
 
@@ -525,60 +807,118 @@ impl RbHttpResponse {
         self.data.status
     }
 
-    fn body(&self) -> String {
+    /// The body, tagged with its detected encoding without altering its bytes,
+    /// or a `Body` to read it from when streaming.
+    fn body(&self) -> Result<Value, MagnusError> {
         match &self.data.body {
-            Some(body) => body.clone(),
-            None => String::new(),
+            ResponseBody::Buffered(_) => Ok(self.text()?.into_value()),
+            ResponseBody::Streaming(stream) => Ok(RbBody::new(stream.clone()).into_value()),
         }
     }
 
-    fn to_s(&self) -> String {
-        self.body()
+    /// The raw body, as an ASCII-8BIT string.
+    fn bytes(&self) -> Result<RString, MagnusError> {
+        Ok(RString::from_slice(&self.data.contents()?))
     }
 
-    fn headers(&self) -> HashMap<String, String> {
-        self.data.headers.clone()
+    /// Decodes the body according to its `Content-Type`, or the MIME type
+    /// given: `parse`, `parse("application/json", symbolize_keys: true)`.
+    fn parse(&self, args: &[Value]) -> Result<Value, MagnusError> {
+        let args = scan_args::<(), (Option<String>,), (), (), RHash, ()>(args)?;
+        let (mime_type,) = args.optional;
+        let kwargs = get_kwargs::<_, (), (Option<bool>, Option<bool>), ()>(
+            args.keywords,
+            &[],
+            &["symbolize_keys", "freeze"],
+        )?;
+        let (symbolize_keys, freeze) = kwargs.optional;
+        let options = json::DecodeOptions {
+            symbolize_keys: symbolize_keys.unwrap_or(false),
+            freeze: freeze.unwrap_or(false),
+        };
+
+        let Some(mime_type) = mime_type.or_else(|| self.content_type()) else {
+            return Err(MagnusError::new(
+                exception::arg_error(),
+                "response has no Content-Type, pass the MIME type to parse",
+            ));
+        };
+
+        let body = self.data.contents()?;
+        parse::decode(
+            &charset::mime_type(&mime_type),
+            &body,
+            |body| self.text_of(body),
+            &self.data.url,
+            options,
+        )
+    }
+
+    /// The whole body, tagged with its detected encoding.
+    fn text(&self) -> Result<RString, MagnusError> {
+        Ok(self.text_of(&self.data.contents()?))
+    }
+
+    /// `body` as a String in the response's encoding.
+    fn text_of(&self, body: &[u8]) -> RString {
+        let content_type = self.content_type();
+        RString::enc_new(body, charset::encoding(content_type.as_deref(), body))
+    }
+
+    /// `to_s` returns the whole body like `body`; `to_s(encoding: "UTF-8")`
+    /// transcodes it, replacing anything that can't be converted.
+    fn to_s(&self, args: &[Value]) -> Result<RString, MagnusError> {
+        let args = scan_args::<(), (Option<RHash>,), (), (), (), ()>(args)?;
+        let body = self.text()?;
+        let Some(options) = args.optional.0 else {
+            return Ok(body);
+        };
+
+        let kwargs = get_kwargs::<_, (), (Option<Value>,), ()>(options, &[], &["encoding"])?;
+        let Some(encoding) = kwargs.optional.0 else {
+            return Ok(body);
+        };
+
+        let replace = RHash::new();
+        replace.aset(Symbol::new("invalid"), Symbol::new("replace"))?;
+        replace.aset(Symbol::new("undef"), Symbol::new("replace"))?;
+        body.funcall("encode", (encoding, KwArgs(replace)))
+    }
+
+    fn headers(&self) -> RbHeaders {
+        RbHeaders::new(self.data.headers.clone())
     }
 
     fn content_type(&self) -> Option<String> {
-        self.data.headers.get("content-type").cloned()
+        self.data
+            .headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
     }
 
     fn uri(&self) -> String {
         self.data.url.clone()
     }
 
+    /// The redirects followed to get this response, oldest first.
+    fn history(&self) -> Result<RArray, MagnusError> {
+        redirect::history(&self.data.history)
+    }
+
     fn code(&self) -> u16 {
         self.status()
     }
 
     fn charset(&self) -> Option<String> {
-        if let Some(content_type) = self.content_type() {
-            if let Some(charset_part) = content_type
-                .split(';')
-                .skip(1)
-                .find(|part| part.trim().to_lowercase().starts_with("charset="))
-            {
-                let charset = charset_part
-                    .trim()
-                    .split('=')
-                    .nth(1)
-                    .unwrap_or("")
-                    .trim()
-                    .to_string();
-
-                if !charset.is_empty() {
-                    return Some(charset);
-                }
-            }
-        }
-        None
+        self.content_type()
+            .as_deref()
+            .and_then(charset::from_content_type)
     }
 }
 
-fn rb_get(url: String) -> Result<RbHttpResponse, MagnusError> {
-    let client = RbHttpClient::new();
-    client.get(url)
+fn rb_get(args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+    RbHttpClient::shared().get(args)
 }
 
 fn rb_desktop() -> RbHttpClient {
@@ -589,42 +929,84 @@ fn rb_mobile() -> RbHttpClient {
     RbHttpClient::new_mobile()
 }
 
+fn rb_emulation(name: Value) -> Result<RbHttpClient, MagnusError> {
+    Ok(RbHttpClient::with_emulation(emulation::from_value(name)?))
+}
+
+fn rb_emulations() -> RArray {
+    emulation::names()
+}
+
 fn rb_post(args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
-    let client = RbHttpClient::new();
-    client.post(args)
+    RbHttpClient::shared().post(args)
 }
 
 fn rb_put(args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
-    let client = RbHttpClient::new();
-    client.put(args)
+    RbHttpClient::shared().put(args)
 }
 
-fn rb_delete(url: String) -> Result<RbHttpResponse, MagnusError> {
-    let client = RbHttpClient::new();
-    client.delete(url)
+fn rb_delete(args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+    RbHttpClient::shared().delete(args)
 }
 
-fn rb_head(url: String) -> Result<RbHttpResponse, MagnusError> {
-    let client = RbHttpClient::new();
-    client.head(url)
+fn rb_head(args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+    RbHttpClient::shared().head(args)
 }
 
 fn rb_patch(args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
-    let client = RbHttpClient::new();
-    client.patch(args)
+    RbHttpClient::shared().patch(args)
+}
+
+fn rb_options(args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+    RbHttpClient::shared().options(args)
+}
+
+fn rb_trace(args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+    RbHttpClient::shared().trace(args)
 }
 
-fn rb_headers(headers_hash: RHash) -> RbHttpClient {
-    let client = RbHttpClient::new();
-    client.headers(headers_hash)
+fn rb_request(args: &[Value]) -> Result<RbHttpResponse, MagnusError> {
+    RbHttpClient::shared().request(args)
 }
 
-fn rb_follow(follow: bool) -> RbHttpClient {
-    RbHttpClient::new().follow(follow)
+fn rb_parallel(args: &[Value]) -> Result<RArray, MagnusError> {
+    RbHttpClient::shared().parallel(args)
+}
+
+fn rb_each_completed(args: &[Value]) -> Result<(), MagnusError> {
+    RbHttpClient::shared().each_completed(args)
+}
+
+fn rb_headers(headers: Value) -> Result<RbHttpClient, MagnusError> {
+    RbHttpClient::shared().headers(headers)
+}
+
+fn rb_follow(args: &[Value]) -> Result<RbHttpClient, MagnusError> {
+    RbHttpClient::shared().follow(args)
 }
 
 fn rb_proxy(proxy: String) -> RbHttpClient {
-    RbHttpClient::new().with_proxy(proxy)
+    RbHttpClient::shared().with_proxy(proxy)
+}
+
+fn rb_params_style(style: Value) -> Result<RbHttpClient, MagnusError> {
+    RbHttpClient::shared().params_style(style)
+}
+
+fn rb_cookies(args: &[Value]) -> Result<RbHttpClient, MagnusError> {
+    RbHttpClient::shared().cookies(args)
+}
+
+fn rb_accept(mime_type: Value) -> Result<RbHttpClient, MagnusError> {
+    RbHttpClient::shared().accept(mime_type)
+}
+
+fn rb_stream() -> RbHttpClient {
+    RbHttpClient::shared().streaming()
+}
+
+fn rb_timeout(options: Value) -> Result<RbHttpClient, MagnusError> {
+    RbHttpClient::shared().timeout(options)
 }
 
 #[magnus::init]
@@ -632,42 +1014,77 @@ fn init(ruby: &magnus::Ruby) -> Result<(), MagnusError> {
     let rquest_module = ruby.define_module("Rquest")?;
     let http_module = rquest_module.define_module("HTTP")?;
 
+    error::define_errors(ruby, http_module)?;
+    form::define_form_data(ruby, http_module)?;
+    headers::define_headers(ruby, http_module)?;
+    cookies::define_cookie_jar(ruby, http_module)?;
+
     let response_class = http_module.define_class("Response", ruby.class_object())?;
     response_class.define_method("status", method!(RbHttpResponse::status, 0))?;
     response_class.define_method("body", method!(RbHttpResponse::body, 0))?;
-    response_class.define_method("to_s", method!(RbHttpResponse::to_s, 0))?;
+    response_class.define_method("bytes", method!(RbHttpResponse::bytes, 0))?;
+    response_class.define_method("to_s", method!(RbHttpResponse::to_s, -1))?;
     response_class.define_method("headers", method!(RbHttpResponse::headers, 0))?;
     response_class.define_method("content_type", method!(RbHttpResponse::content_type, 0))?;
     response_class.define_method("uri", method!(RbHttpResponse::uri, 0))?;
+    response_class.define_method("history", method!(RbHttpResponse::history, 0))?;
     response_class.define_method("code", method!(RbHttpResponse::code, 0))?;
     response_class.define_method("charset", method!(RbHttpResponse::charset, 0))?;
+    response_class.define_method("parse", method!(RbHttpResponse::parse, -1))?;
+    body::define_body(ruby, response_class)?;
 
     let client_class = http_module.define_class("Client", ruby.class_object())?;
-    client_class.define_singleton_method("new", function!(RbHttpClient::new, 0))?;
+    client_class.define_singleton_method("new", function!(RbHttpClient::rb_new, -1))?;
     client_class.define_singleton_method("new_desktop", function!(RbHttpClient::new_desktop, 0))?;
     client_class.define_singleton_method("new_mobile", function!(RbHttpClient::new_mobile, 0))?;
     client_class.define_method("with_headers", method!(RbHttpClient::with_headers, 1))?;
-    client_class.define_method("follow", method!(RbHttpClient::follow, 1))?;
+    client_class.define_method("follow", method!(RbHttpClient::follow, -1))?;
     client_class.define_method("with_proxy", method!(RbHttpClient::with_proxy, 1))?;
-    client_class.define_method("get", method!(RbHttpClient::get, 1))?;
+    client_class.define_method("emulation", method!(RbHttpClient::emulation, 1))?;
+    client_class.define_method("timeout", method!(RbHttpClient::timeout, 1))?;
+    client_class.define_method("accept", method!(RbHttpClient::accept, 1))?;
+    client_class.define_method("cookies", method!(RbHttpClient::cookies, -1))?;
+    client_class.define_method("cookie_jar", method!(RbHttpClient::cookie_jar, 0))?;
+    client_class.define_method("stream", method!(RbHttpClient::streaming, 0))?;
+    client_class.define_method("params_style", method!(RbHttpClient::params_style, 1))?;
+    client_class.define_method("get", method!(RbHttpClient::get, -1))?;
     client_class.define_method("post", method!(RbHttpClient::post, -1))?;
     client_class.define_method("put", method!(RbHttpClient::put, -1))?;
-    client_class.define_method("delete", method!(RbHttpClient::delete, 1))?;
-    client_class.define_method("head", method!(RbHttpClient::head, 1))?;
+    client_class.define_method("delete", method!(RbHttpClient::delete, -1))?;
+    client_class.define_method("head", method!(RbHttpClient::head, -1))?;
     client_class.define_method("patch", method!(RbHttpClient::patch, -1))?;
+    client_class.define_method("options", method!(RbHttpClient::options, -1))?;
+    client_class.define_method("trace", method!(RbHttpClient::trace, -1))?;
+    client_class.define_method("request", method!(RbHttpClient::request, -1))?;
     client_class.define_method("headers", method!(RbHttpClient::headers, 1))?;
+    client_class.define_method("parallel", method!(RbHttpClient::parallel, -1))?;
+    client_class.define_method("each_completed", method!(RbHttpClient::each_completed, -1))?;
 
-    http_module.define_module_function("get", function!(rb_get, 1))?;
+    http_module.define_module_function("get", function!(rb_get, -1))?;
     http_module.define_module_function("desktop", function!(rb_desktop, 0))?;
     http_module.define_module_function("mobile", function!(rb_mobile, 0))?;
+    http_module.define_module_function("emulation", function!(rb_emulation, 1))?;
+    http_module.define_module_function("emulations", function!(rb_emulations, 0))?;
     http_module.define_module_function("post", function!(rb_post, -1))?;
     http_module.define_module_function("put", function!(rb_put, -1))?;
-    http_module.define_module_function("delete", function!(rb_delete, 1))?;
-    http_module.define_module_function("head", function!(rb_head, 1))?;
+    http_module.define_module_function("delete", function!(rb_delete, -1))?;
+    http_module.define_module_function("head", function!(rb_head, -1))?;
     http_module.define_module_function("patch", function!(rb_patch, -1))?;
+    http_module.define_module_function("options", function!(rb_options, -1))?;
+    http_module.define_module_function("trace", function!(rb_trace, -1))?;
+    http_module.define_module_function("request", function!(rb_request, -1))?;
     http_module.define_module_function("headers", function!(rb_headers, 1))?;
-    http_module.define_module_function("follow", function!(rb_follow, 1))?;
+    http_module.define_module_function("parallel", function!(rb_parallel, -1))?;
+    http_module.define_module_function("each_completed", function!(rb_each_completed, -1))?;
+    http_module.define_module_function("follow", function!(rb_follow, -1))?;
     http_module.define_module_function("proxy", function!(rb_proxy, 1))?;
+    http_module.define_module_function("timeout", function!(rb_timeout, 1))?;
+    http_module.define_module_function("accept", function!(rb_accept, 1))?;
+    http_module.define_module_function("cookies", function!(rb_cookies, -1))?;
+    http_module.define_module_function("stream", function!(rb_stream, 0))?;
+    http_module.define_module_function("params_style", function!(rb_params_style, 1))?;
+    http_module
+        .define_module_function("register_decoder", function!(parse::register_decoder, -1))?;
 
     Ok(())
 }
@@ -699,16 +1116,26 @@ mod tests {
         });
     }
 
+    fn url(url: &str) -> Value {
+        RString::new(url).as_value()
+    }
+
+    /// Sends a request through the async path directly, since
+    /// `gvl::block_on` only works on the thread Ruby runs on.
+    fn fetch(client: &RbHttpClient, method: Method, url: &str) -> RbHttpResponse {
+        let (req, _) = client.prepare(method, url, None).unwrap();
+        match get_runtime().block_on(client.perform(req)) {
+            Ok(data) => RbHttpResponse::new(data),
+            Err(_) => panic!("request to {} failed", url),
+        }
+    }
+
     // No longer needed as proxy test is skipped
 
     #[test]
     #[serial]
     fn test_http_client_basic() {
-        init_ruby();
-
-        let response = RbHttpClient::new()
-            .get("https://httpbin.org/get".to_string())
-            .unwrap();
+        let response = fetch(&RbHttpClient::new(), Method::GET, "https://httpbin.org/get");
         assert_eq!(response.status(), 200);
     }
 
@@ -742,22 +1169,22 @@ mod tests {
     #[test]
     #[serial]
     fn test_http_client_delete() {
-        init_ruby();
-
-        let response = RbHttpClient::new()
-            .delete("https://httpbin.org/delete".to_string())
-            .unwrap();
+        let response = fetch(
+            &RbHttpClient::new(),
+            Method::DELETE,
+            "https://httpbin.org/delete",
+        );
         assert_eq!(response.status(), 200);
     }
 
     #[test]
     #[serial]
     fn test_http_client_head() {
-        init_ruby();
-
-        let response = RbHttpClient::new()
-            .head("https://httpbin.org/get".to_string())
-            .unwrap();
+        let response = fetch(
+            &RbHttpClient::new(),
+            Method::HEAD,
+            "https://httpbin.org/get",
+        );
         assert_eq!(response.status(), 200);
     }
 
@@ -773,14 +1200,333 @@ mod tests {
     #[test]
     #[serial]
     fn test_http_response() {
-        init_ruby();
-
-        let client = RbHttpClient::new();
-        let response = client.get("https://httpbin.org/get".to_string()).unwrap();
+        let response = fetch(&RbHttpClient::new(), Method::GET, "https://httpbin.org/get");
 
         assert_eq!(response.status(), 200);
-        assert!(response.body().contains("httpbin.org"));
-        assert!(response.headers().contains_key("content-type"));
+        assert!(
+            String::from_utf8_lossy(&response.data.contents().unwrap()).contains("httpbin.org")
+        );
+        assert!(response.data.headers.contains_key("content-type"));
         assert!(response.uri().contains("httpbin.org"));
     }
+
+    #[test]
+    #[serial]
+    fn test_parse_method() {
+        init_ruby();
+
+        let options = Symbol::new("options").as_value();
+        assert_eq!(parse_method(options).unwrap(), Method::OPTIONS);
+        assert_eq!(parse_method(url("PROPFIND")).unwrap().as_str(), "PROPFIND");
+        assert_eq!(parse_method(url("propfind")).unwrap().as_str(), "propfind");
+        assert_eq!(parse_method(url("get")).unwrap(), Method::GET);
+        assert_eq!(parse_method(url("Patch")).unwrap(), Method::PATCH);
+        let propfind = Symbol::new("propfind").as_value();
+        assert_eq!(parse_method(propfind).unwrap().as_str(), "PROPFIND");
+        assert!(parse_method(url("BAD METHOD")).is_err());
+    }
+
+    #[test]
+    fn test_emulation_lookup() {
+        assert!(matches!(
+            emulation::find("chrome134"),
+            Some(RquestEmulation::Chrome134)
+        ));
+        assert!(matches!(
+            emulation::find("safari_ios_17_4_1"),
+            Some(RquestEmulation::SafariIos17_4_1)
+        ));
+        assert!(matches!(
+            emulation::find("Safari_IOS_17.4.1"),
+            Some(RquestEmulation::SafariIos17_4_1)
+        ));
+        assert!(emulation::find("netscape4").is_none());
+    }
+
+    #[test]
+    fn test_emulations_match_rquest_util() {
+        use strum::VariantArray;
+
+        for variant in RquestEmulation::VARIANTS {
+            let name = format!("{:?}", variant);
+            assert!(
+                emulation::EMULATIONS
+                    .iter()
+                    .any(|(_, emulation)| format!("{:?}", emulation) == name),
+                "{} is missing from EMULATIONS",
+                name
+            );
+        }
+        for (name, emulation) in emulation::EMULATIONS {
+            let variant = format!("{:?}", emulation);
+            assert_eq!(
+                emulation::find(name).map(|found| format!("{:?}", found)),
+                Some(variant),
+                "{} doesn't name its variant",
+                name
+            );
+        }
+        assert_eq!(emulation::EMULATIONS.len(), RquestEmulation::VARIANTS.len());
+    }
+
+    #[test]
+    fn test_charset_detection() {
+        assert_eq!(
+            charset::detect(Some("text/html; charset=Shift_JIS"), b"<html>"),
+            Some("Shift_JIS".to_string())
+        );
+        assert_eq!(
+            charset::detect(Some("text/plain"), b"\xEF\xBB\xBFhello"),
+            Some("UTF-8".to_string())
+        );
+        assert_eq!(
+            charset::detect(
+                Some("text/html"),
+                b"<html><head><meta charset=\"windows-1251\"></head>"
+            ),
+            Some("windows-1251".to_string())
+        );
+        assert_eq!(
+            charset::detect(
+                None,
+                b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=gbk\">"
+            ),
+            Some("gbk".to_string())
+        );
+        assert_eq!(
+            charset::detect(Some("application/json"), b"<meta charset=\"gbk\">"),
+            None
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_json_generate() {
+        init_ruby();
+
+        let value: Value = magnus::eval(
+            r#"{ name: "test", tags: [:a, 1, 2.5, nil, true], "nested" => { 1 => false } }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            json::generate(value).unwrap(),
+            r#"{"name":"test","tags":["a",1,2.5,null,true],"nested":{"1":false}}"#
+        );
+
+        let value: Value = magnus::eval("{ user: { tags: [1, Object.new] } }").unwrap();
+        let error = json::generate(value).unwrap_err();
+        assert!(error.to_string().contains("$.user.tags[1]"));
+
+        let value: Value = magnus::eval("h = {}; h[:a] = h; h").unwrap();
+        let error = json::generate(value).unwrap_err();
+        assert!(error.is_kind_of(exception::arg_error()));
+        assert!(error.to_string().contains("too deep (at $.a.a."));
+
+        let value: Value = magnus::eval("(1..100).reduce(1) { |v, _| [v] }").unwrap();
+        assert!(json::generate(value).is_ok());
+        let value: Value = magnus::eval("(1..101).reduce(1) { |v, _| [v] }").unwrap();
+        assert!(json::generate(value).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_json_to_ruby() {
+        init_ruby();
+
+        let options = json::DecodeOptions {
+            symbolize_keys: true,
+            freeze: true,
+        };
+        let value = json::to_ruby(
+            serde_json::json!({ "a": [1, "x", null], "b": 1.5 }),
+            options,
+        )
+        .unwrap();
+
+        let hash = RHash::from_value(value).unwrap();
+        let array = RArray::from_value(hash.get(Symbol::new("a")).unwrap()).unwrap();
+        assert_eq!(array.len(), 3);
+        assert_eq!(String::try_convert(array.entry(1).unwrap()).unwrap(), "x");
+        assert_eq!(
+            f64::try_convert(hash.get(Symbol::new("b")).unwrap()).unwrap(),
+            1.5
+        );
+        assert!(hash.is_frozen() && array.is_frozen());
+    }
+
+    #[test]
+    #[serial]
+    fn test_query_merge() {
+        init_ruby();
+
+        let params: RHash =
+            magnus::eval(r#"{ q: "a b&c", tags: ["x", "y"], filter: { status: nil } }"#).unwrap();
+        let url = "https://example.com/search?page=2";
+
+        assert_eq!(
+            query::merge(url, params, ArrayStyle::Repeat).unwrap(),
+            "https://example.com/search?page=2&q=a+b%26c&tags=x&tags=y&filter%5Bstatus%5D="
+        );
+        assert_eq!(
+            query::merge(url, params, ArrayStyle::Brackets).unwrap(),
+            "https://example.com/search?page=2&q=a+b%26c&tags%5B%5D=x&tags%5B%5D=y&filter%5Bstatus%5D="
+        );
+        assert_eq!(
+            query::merge(url, params, ArrayStyle::Comma).unwrap(),
+            "https://example.com/search?page=2&q=a+b%26c&tags=x,y&filter%5Bstatus%5D="
+        );
+
+        let params: RHash =
+            magnus::eval(r#"{ items: [{ id: 1 }, { id: 2 }], grid: [[1, 2]] }"#).unwrap();
+        assert_eq!(
+            query::merge(url, params, ArrayStyle::Repeat).unwrap(),
+            "https://example.com/search?page=2&items%5B%5D%5Bid%5D=1&items%5B%5D%5Bid%5D=2&grid%5B%5D=1&grid%5B%5D=2"
+        );
+        assert_eq!(
+            query::merge(url, params, ArrayStyle::Brackets).unwrap(),
+            "https://example.com/search?page=2&items%5B%5D%5Bid%5D=1&items%5B%5D%5Bid%5D=2&grid%5B%5D%5B%5D=1&grid%5B%5D%5B%5D=2"
+        );
+        assert_eq!(
+            query::merge(url, params, ArrayStyle::Comma).unwrap(),
+            "https://example.com/search?page=2&items%5B%5D%5Bid%5D=1&items%5B%5D%5Bid%5D=2&grid%5B%5D=1,2"
+        );
+
+        let nested: RHash = magnus::eval("h = {}; h[:a] = h; h").unwrap();
+        let error = query::merge(url, nested, ArrayStyle::Repeat).unwrap_err();
+        assert!(error.is_kind_of(exception::arg_error()));
+        assert!(error.to_string().contains("too deep (at a[a][a]"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_header_map() {
+        init_ruby();
+
+        let mut headers = headers::header_map(
+            magnus::eval(r#"{ user_agent: "App/1.0", "Accept" => "text/html", accept: "*/*" }"#)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(headers["user-agent"], "App/1.0");
+        assert_eq!(headers["accept"], "*/*");
+
+        let other =
+            headers::header_map(magnus::eval(r#"{ "ACCEPT" => "application/json" }"#).unwrap())
+                .unwrap();
+        headers::merge(&mut headers, &other);
+        assert_eq!(headers["accept"], "application/json");
+        assert_eq!(headers["user-agent"], "App/1.0");
+
+        assert!(headers::header_map(magnus::eval(r#"{ "X-Test" => "a\r\nb" }"#).unwrap()).is_err());
+        assert!(headers::header_map(magnus::eval(r#"{ "X\nTest" => "a" }"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_timeout_refine() {
+        let secs = Duration::from_secs;
+        let timeouts = Timeouts {
+            total: Some(secs(10)),
+            read: Some(secs(2)),
+            ..Timeouts::default()
+        };
+        assert_eq!(
+            timeouts.refine(ErrorKind::Timeout, secs(10)),
+            ErrorKind::Timeout
+        );
+        assert_eq!(
+            timeouts.refine(ErrorKind::Timeout, secs(3)),
+            ErrorKind::ReadTimeout
+        );
+        assert_eq!(
+            timeouts.refine(ErrorKind::Timeout, secs(1)),
+            ErrorKind::Timeout
+        );
+        assert_eq!(
+            timeouts.refine(ErrorKind::Connection, secs(3)),
+            ErrorKind::Connection
+        );
+
+        let total_only = Timeouts {
+            total: Some(secs(5)),
+            ..Timeouts::default()
+        };
+        assert_eq!(
+            total_only.refine(ErrorKind::Timeout, secs(5)),
+            ErrorKind::Timeout
+        );
+    }
+
+    #[test]
+    fn test_cookie_jar() {
+        use rquest::cookie::CookieStore;
+
+        let jar = CookieJar::default();
+        let url = url::Url::parse("https://example.com/account/login").unwrap();
+        let set_cookies = [
+            HeaderValue::from_static("session=abc; Path=/; HttpOnly"),
+            HeaderValue::from_static("theme=dark; Path=/account"),
+        ];
+        jar.set_cookies(&mut set_cookies.iter(), &url);
+
+        let home = url::Url::parse("https://example.com/").unwrap();
+        assert_eq!(jar.cookies(&home).unwrap(), "session=abc");
+        let cookies = jar.cookies(&url).unwrap();
+        assert!(cookies.to_str().unwrap().contains("theme=dark"));
+
+        let other = url::Url::parse("https://example.org/").unwrap();
+        assert!(jar.cookies(&other).is_none());
+    }
+
+    #[test]
+    #[serial]
+    fn test_redirect_options() {
+        init_ruby();
+
+        let redirects = Redirects::from_value(magnus::eval("false").unwrap()).unwrap();
+        assert!(!redirects.follow);
+
+        let redirects = Redirects::from_value(magnus::eval("{ max_hops: 3 }").unwrap()).unwrap();
+        assert!(redirects.follow);
+        assert_eq!(redirects.max_hops, 3);
+
+        assert!(Redirects::from_value(magnus::eval("{ hops: 3 }").unwrap()).is_err());
+        assert!(Redirects::from_value(magnus::eval(r#"{ on_redirect: "x" }"#).unwrap()).is_err());
+
+        let from = url::Url::parse("https://example.com/login").unwrap();
+        let same = url::Url::parse("https://example.com/account").unwrap();
+        let other = url::Url::parse("https://cdn.example.net/file").unwrap();
+        let http = url::Url::parse("http://example.com/account").unwrap();
+        let sent = || {
+            let mut headers = HeaderMap::new();
+            headers.insert("authorization", HeaderValue::from_static("Bearer t"));
+            headers.insert("cookie", HeaderValue::from_static("a=1"));
+            headers.insert("x-api-key", HeaderValue::from_static("k"));
+            headers
+        };
+
+        let redirects = Redirects::default();
+        let mut headers = sent();
+        redirects.strip(&from, &same, &mut headers);
+        assert_eq!(headers.len(), 3);
+        for to in [&other, &http] {
+            let mut headers = sent();
+            redirects.strip(&from, to, &mut headers);
+            assert_eq!(headers.keys().collect::<Vec<_>>(), ["x-api-key"]);
+        }
+
+        let redirects = Redirects::from_value(
+            magnus::eval(
+                r#"{ sensitive_headers: [:x_api_key], trusted_hosts: ["CDN.example.net"] }"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let mut headers = sent();
+        redirects.strip(&from, &other, &mut headers);
+        assert_eq!(headers.len(), 3);
+        let mut headers = sent();
+        redirects.strip(&from, &http, &mut headers);
+        assert!(!headers.contains_key("x-api-key"));
+        assert!(headers.contains_key("authorization"));
+    }
 }
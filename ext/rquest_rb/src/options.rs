@@ -0,0 +1,149 @@
+use crate::form::Form;
+use crate::headers::header_map;
+use crate::redirect::Redirects;
+use crate::upload::Upload;
+use crate::{Timeouts, emulation, json};
+use magnus::scan_args::{get_kwargs, scan_args};
+use magnus::{Error as MagnusError, RHash, TryConvert, Value, exception};
+use rquest::RequestBuilder;
+use rquest::header::HeaderMap;
+use rquest_util::Emulation as RquestEmulation;
+
+/// A request body: a String sent as is, an IO or Enumerator streamed from Ruby
+/// as it is sent, a form, or serialized JSON.
+pub(crate) enum RequestBody {
+    Text(String),
+    Upload(Upload),
+    Form(Form),
+    Json(String),
+}
+
+impl RequestBody {
+    /// A `body:` value: a String, or an IO or Enumerator to stream.
+    fn from_value(body: Value) -> Result<Self, MagnusError> {
+        match Upload::from_value(body)? {
+            Some(upload) => Ok(RequestBody::Upload(upload)),
+            None => Ok(RequestBody::Text(String::try_convert(body)?)),
+        }
+    }
+}
+
+type OptionKwargs = (
+    Option<Value>,
+    Option<RHash>,
+    Option<Value>,
+    Option<Value>,
+    Option<RHash>,
+    Option<Value>,
+    Option<Value>,
+    Option<String>,
+    Option<Value>,
+);
+
+/// The options every verb takes, applying to that one request only.
+#[derive(Default)]
+pub(crate) struct RequestOptions {
+    pub(crate) headers: Option<HeaderMap>,
+    pub(crate) params: Option<RHash>,
+    pub(crate) body: Option<RequestBody>,
+    pub(crate) timeouts: Option<Timeouts>,
+    pub(crate) redirects: Option<Redirects>,
+    pub(crate) overrides: RequestOverrides,
+}
+
+/// The options that rquest supports per request without a new client. They
+/// are kept with the client a request is sent from, so that redirects use
+/// them too.
+#[derive(Clone, Default)]
+pub(crate) struct RequestOverrides {
+    proxy: Option<String>,
+    emulation: Option<RquestEmulation>,
+}
+
+impl RequestOverrides {
+    pub(crate) fn apply(&self, mut req: RequestBuilder) -> RequestBuilder {
+        if let Some(proxy) = &self.proxy {
+            req = req.proxy(proxy.clone());
+        }
+        if let Some(emulation) = self.emulation {
+            req = req.emulation(emulation);
+        }
+        req
+    }
+}
+
+impl RequestOptions {
+    /// Parses `(url)`, `(url, options)`, or `(url, body)` for a String or IO
+    /// body given on its own.
+    pub(crate) fn parse(args: &[Value]) -> Result<(String, Self), MagnusError> {
+        let args = scan_args::<(String,), (Option<Value>,), (), (), (), ()>(args)?;
+        let (url,) = args.required;
+        let (options,) = args.optional;
+
+        let options = match options {
+            None => Self::default(),
+            Some(options) => match RHash::from_value(options) {
+                Some(options) => Self::from_hash(options)?,
+                None => Self {
+                    body: Some(RequestBody::from_value(options)?),
+                    ..Self::default()
+                },
+            },
+        };
+
+        Ok((url, options))
+    }
+
+    fn from_hash(options: RHash) -> Result<Self, MagnusError> {
+        let kwargs = get_kwargs::<_, (), OptionKwargs, ()>(
+            options,
+            &[],
+            &[
+                "headers",
+                "params",
+                "body",
+                "json",
+                "form",
+                "timeout",
+                "follow",
+                "proxy",
+                "emulation",
+            ],
+        )?;
+        let (headers, params, body, json, form, timeout, follow, proxy, emulation) =
+            kwargs.optional;
+
+        let body = match (body, json, form) {
+            (None, None, None) => None,
+            (Some(body), None, None) => Some(RequestBody::from_value(body)?),
+            (None, Some(json), None) => Some(RequestBody::Json(json::generate(json)?)),
+            (None, None, Some(form)) => Some(RequestBody::Form(Form::from_hash(form)?)),
+            _ => {
+                return Err(MagnusError::new(
+                    exception::arg_error(),
+                    "only one of body:, form: and json: can be given",
+                ));
+            }
+        };
+
+        let timeouts = timeout.map(Timeouts::from_value).transpose()?;
+        if timeouts.is_some_and(|timeouts| timeouts.connect.is_some()) {
+            return Err(MagnusError::new(
+                exception::arg_error(),
+                "a connect timeout can only be set with Client#timeout",
+            ));
+        }
+
+        Ok(Self {
+            headers: headers.map(header_map).transpose()?,
+            params,
+            body,
+            timeouts,
+            redirects: follow.map(Redirects::from_value).transpose()?,
+            overrides: RequestOverrides {
+                proxy,
+                emulation: emulation.map(emulation::from_value).transpose()?,
+            },
+        })
+    }
+}
@@ -0,0 +1,94 @@
+use crate::error::{self, ErrorKind};
+use crate::json::{self, DecodeOptions};
+use magnus::block::Proc;
+use magnus::scan_args::scan_args;
+use magnus::value::ReprValue;
+use magnus::{Error as MagnusError, RHash, RString, Ruby, Value, exception};
+
+/// The module instance variable holding decoders registered from Ruby. Keeping
+/// them on the module keeps them alive for the GC.
+const DECODERS: &str = "@decoders";
+
+fn decoders(ruby: &Ruby) -> Result<RHash, MagnusError> {
+    let http_module = error::http_module(ruby);
+    match http_module.ivar_get::<_, Option<RHash>>(DECODERS)? {
+        Some(decoders) => Ok(decoders),
+        None => {
+            let decoders = RHash::new();
+            http_module.ivar_set(DECODERS, decoders)?;
+            Ok(decoders)
+        }
+    }
+}
+
+fn is_json(mime_type: &str) -> bool {
+    mime_type == "application/json" || mime_type.ends_with("+json")
+}
+
+/// `Rquest::HTTP.register_decoder("text/csv") { |body| CSV.parse(body) }`, or
+/// with any object responding to `call` in place of the block.
+///
+/// Registered decoders take precedence over the built-in JSON decoder.
+pub(crate) fn register_decoder(args: &[Value]) -> Result<(), MagnusError> {
+    let ruby = Ruby::get().expect("decoders are registered from a Ruby thread");
+    let args = scan_args::<(String,), (Option<Value>,), (), (), (), Option<Proc>>(args)?;
+    let (mime_type,) = args.required;
+    let (decoder,) = args.optional;
+
+    let decoder = match (decoder, args.block) {
+        (Some(decoder), None) => decoder,
+        (None, Some(block)) => block.as_value(),
+        _ => {
+            return Err(MagnusError::new(
+                exception::arg_error(),
+                "expected either a decoder or a block",
+            ));
+        }
+    };
+    if !decoder.respond_to("call", false)? {
+        return Err(MagnusError::new(
+            exception::type_error(),
+            "decoder must respond to call",
+        ));
+    }
+
+    decoders(&ruby)?.aset(mime_type.to_ascii_lowercase(), decoder)
+}
+
+/// Decodes a response body of the given MIME type: with a registered decoder,
+/// which is passed the body as a String built by `text`, or natively when it
+/// is JSON, without building a String at all.
+pub(crate) fn decode(
+    mime_type: &str,
+    body: &[u8],
+    text: impl FnOnce(&[u8]) -> RString,
+    url: &str,
+    options: DecodeOptions,
+) -> Result<Value, MagnusError> {
+    let ruby = Ruby::get().expect("responses are parsed on a Ruby thread");
+    let parse_error = |message: String| {
+        error::new_error(
+            &ruby,
+            ErrorKind::Parse,
+            message.clone(),
+            None,
+            url,
+            Some(message),
+        )
+    };
+
+    if let Some(decoder) = decoders(&ruby)?.get(mime_type) {
+        return decoder.funcall("call", (text(body),));
+    }
+
+    if is_json(mime_type) {
+        let json = serde_json::from_slice(body)
+            .map_err(|e| parse_error(format!("invalid JSON: {}", e)))?;
+        return json::to_ruby(json, options);
+    }
+
+    Err(parse_error(format!(
+        "no decoder registered for {}",
+        mime_type
+    )))
+}
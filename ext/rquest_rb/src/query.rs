@@ -0,0 +1,169 @@
+use crate::{MAX_NESTING, nesting_error};
+use magnus::r_hash::ForEach;
+use magnus::value::ReprValue;
+use magnus::{Error as MagnusError, RArray, RHash, Symbol, TryConvert, Value, exception};
+use url::Url;
+use url::form_urlencoded::byte_serialize;
+
+/// How array values in `params:` are written to the query string.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum ArrayStyle {
+    /// `tags=x&tags=y`
+    #[default]
+    Repeat,
+    /// `tags[]=x&tags[]=y`
+    Brackets,
+    /// `tags=x,y`
+    Comma,
+}
+
+impl ArrayStyle {
+    pub(crate) fn from_value(value: Value) -> Result<Self, MagnusError> {
+        let name = match Symbol::from_value(value) {
+            Some(symbol) => symbol.name()?.into_owned(),
+            None => String::try_convert(value)?,
+        };
+
+        match name.as_str() {
+            "repeat" => Ok(ArrayStyle::Repeat),
+            "brackets" => Ok(ArrayStyle::Brackets),
+            "comma" => Ok(ArrayStyle::Comma),
+            _ => Err(MagnusError::new(
+                exception::arg_error(),
+                format!(
+                    "unknown array style: {} (expected :repeat, :brackets or :comma)",
+                    name
+                ),
+            )),
+        }
+    }
+}
+
+/// A query parameter. Several values are only kept together for the comma
+/// style, and are joined with unencoded commas.
+struct Param {
+    name: String,
+    values: Vec<String>,
+}
+
+fn to_param_string(value: Value) -> Result<String, MagnusError> {
+    if value.is_nil() {
+        Ok(String::new())
+    } else {
+        value.funcall("to_s", ())
+    }
+}
+
+/// Flattens `params:` into query parameters. Nested hashes use Rack-style
+/// keys: `{ filter: { status: "open" } }` becomes `filter[status]=open`.
+/// `params` is nested `depth` deep.
+fn flatten(
+    prefix: Option<&str>,
+    params: RHash,
+    style: ArrayStyle,
+    depth: usize,
+    out: &mut Vec<Param>,
+) -> Result<(), MagnusError> {
+    params.foreach(|key: Value, value: Value| {
+        let key = key.funcall::<_, _, String>("to_s", ())?;
+        let name = match prefix {
+            Some(prefix) => format!("{}[{}]", prefix, key),
+            None => key,
+        };
+        add(name, value, style, depth, out)?;
+        Ok(ForEach::Continue)
+    })
+}
+
+/// Adds the parameters for `value`, found in a container `depth` deep.
+///
+/// Hashes and Arrays inside an Array are flattened under `name[]`, as Rack
+/// does: `{ items: [{ id: 1 }] }` becomes `items[][id]=1`. Other elements are
+/// written in `style`.
+fn add(
+    name: String,
+    value: Value,
+    style: ArrayStyle,
+    depth: usize,
+    out: &mut Vec<Param>,
+) -> Result<(), MagnusError> {
+    let container = RHash::from_value(value).is_some() || RArray::from_value(value).is_some();
+    if container && depth >= MAX_NESTING {
+        return Err(nesting_error(&name));
+    }
+
+    if let Some(hash) = RHash::from_value(value) {
+        return flatten(Some(&name), hash, style, depth + 1, out);
+    }
+    let Some(array) = RArray::from_value(value) else {
+        out.push(Param {
+            name,
+            values: vec![to_param_string(value)?],
+        });
+        return Ok(());
+    };
+
+    let items = array.to_vec::<Value>()?;
+    let mut joined = Vec::new();
+    for &item in &items {
+        if RHash::from_value(item).is_some() || RArray::from_value(item).is_some() {
+            add(format!("{}[]", name), item, style, depth + 1, out)?;
+            continue;
+        }
+
+        let item = to_param_string(item)?;
+        match style {
+            ArrayStyle::Repeat => out.push(Param {
+                name: name.clone(),
+                values: vec![item],
+            }),
+            ArrayStyle::Brackets => out.push(Param {
+                name: format!("{}[]", name),
+                values: vec![item],
+            }),
+            ArrayStyle::Comma => joined.push(item),
+        }
+    }
+    // An empty Array is still sent as `name=` in the comma style.
+    if style == ArrayStyle::Comma && (!joined.is_empty() || items.is_empty()) {
+        out.push(Param {
+            name,
+            values: joined,
+        });
+    }
+    Ok(())
+}
+
+fn encode(component: &str) -> String {
+    byte_serialize(component.as_bytes()).collect()
+}
+
+/// Appends `params` to the query of `url`, after any parameters it already has.
+pub(crate) fn merge(url: &str, params: RHash, style: ArrayStyle) -> Result<String, MagnusError> {
+    let mut parsed = Url::parse(url).map_err(|e| {
+        MagnusError::new(
+            exception::arg_error(),
+            format!("invalid URL {}: {}", url, e),
+        )
+    })?;
+
+    let mut flattened = Vec::new();
+    flatten(None, params, style, 1, &mut flattened)?;
+    if flattened.is_empty() {
+        return Ok(url.to_string());
+    }
+
+    let mut query = parsed.query().unwrap_or("").to_string();
+    for param in flattened {
+        if !query.is_empty() {
+            query.push('&');
+        }
+        query.push_str(&encode(&param.name));
+        query.push('=');
+        let values: Vec<String> = param.values.iter().map(|value| encode(value)).collect();
+        query.push_str(&values.join(","));
+    }
+
+    parsed.set_query(Some(&query));
+    Ok(parsed.into())
+}
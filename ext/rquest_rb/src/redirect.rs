@@ -0,0 +1,247 @@
+use crate::headers::{self, RbHeaders};
+use magnus::gc::Marker;
+use magnus::scan_args::{get_kwargs, scan_args};
+use magnus::value::{Opaque, ReprValue};
+use magnus::{
+    Error as MagnusError, IntoValue, RArray, RHash, Ruby, Symbol, TryConvert, Value, exception,
+};
+use rquest::header::{AUTHORIZATION, COOKIE, HeaderMap, HeaderName, LOCATION, PROXY_AUTHORIZATION};
+use rquest::{Method, Response as RquestResponse};
+use url::Url;
+
+const DEFAULT_MAX_HOPS: usize = 10;
+
+/// Headers dropped when a redirect leaves the origin, unless configured
+/// otherwise.
+const SENSITIVE_HEADERS: [HeaderName; 3] = [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION];
+
+/// How a client follows redirects, set with `follow`.
+#[derive(Clone)]
+pub(crate) struct Redirects {
+    pub(crate) follow: bool,
+    pub(crate) max_hops: usize,
+    /// Refuse to turn a POST (or any method but GET and HEAD) into a GET on a
+    /// 301 or 302, as RFC 7231 asks, rather than do what browsers do.
+    strict: bool,
+    /// Called with `(from, to, status)` before each redirect is followed.
+    on_redirect: Option<Opaque<Value>>,
+    /// Removed from the request when a redirect changes scheme, host or port.
+    sensitive_headers: Vec<HeaderName>,
+    /// Hosts that are sent `sensitive_headers` anyway.
+    trusted_hosts: Vec<String>,
+}
+
+impl Default for Redirects {
+    fn default() -> Self {
+        Self {
+            follow: true,
+            max_hops: DEFAULT_MAX_HOPS,
+            strict: false,
+            on_redirect: None,
+            sensitive_headers: SENSITIVE_HEADERS.to_vec(),
+            trusted_hosts: Vec::new(),
+        }
+    }
+}
+
+/// A redirect that was followed, for `Response#history`.
+pub(crate) struct Hop {
+    pub(crate) status: u16,
+    /// The URL that answered with the redirect.
+    pub(crate) url: Url,
+    /// Where it redirected to, resolved against `url`.
+    pub(crate) location: Url,
+    pub(crate) headers: HeaderMap,
+}
+
+/// The request to make for a redirect.
+pub(crate) struct Redirect {
+    pub(crate) hop: Hop,
+    pub(crate) method: Method,
+    /// Whether the request body is sent again, which only 307 and 308 do.
+    pub(crate) keep_body: bool,
+}
+
+impl Redirects {
+    /// `follow`, `follow(false)`, or `follow(max_hops: 5, strict: true,
+    /// on_redirect: ->(from, to, status) { ... }, sensitive_headers: [...],
+    /// trusted_hosts: [...])`.
+    pub(crate) fn from_args(args: &[Value]) -> Result<Self, MagnusError> {
+        let args = scan_args::<(), (Option<Value>,), (), (), (), ()>(args)?;
+        match args.optional.0 {
+            Some(value) => Self::from_value(value),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// `true`, `false`, or a Hash of options, which turns following on.
+    pub(crate) fn from_value(value: Value) -> Result<Self, MagnusError> {
+        let Some(options) = RHash::from_value(value) else {
+            return Ok(Self {
+                follow: bool::try_convert(value)?,
+                ..Self::default()
+            });
+        };
+
+        let kwargs = get_kwargs::<
+            _,
+            (),
+            (
+                Option<usize>,
+                Option<bool>,
+                Option<Value>,
+                Option<Vec<Value>>,
+                Option<Vec<String>>,
+            ),
+            (),
+        >(
+            options,
+            &[],
+            &[
+                "max_hops",
+                "strict",
+                "on_redirect",
+                "sensitive_headers",
+                "trusted_hosts",
+            ],
+        )?;
+        let (max_hops, strict, on_redirect, sensitive_headers, trusted_hosts) = kwargs.optional;
+        match on_redirect {
+            Some(callback) if !callback.respond_to("call", false)? => {
+                return Err(MagnusError::new(
+                    exception::type_error(),
+                    "on_redirect must respond to call",
+                ));
+            }
+            _ => {}
+        }
+
+        Ok(Self {
+            follow: true,
+            max_hops: max_hops.unwrap_or(DEFAULT_MAX_HOPS),
+            strict: strict.unwrap_or(false),
+            on_redirect: on_redirect.map(Opaque::from),
+            sensitive_headers: match sensitive_headers {
+                Some(names) => names
+                    .into_iter()
+                    .map(headers::header_name)
+                    .collect::<Result<_, _>>()?,
+                None => SENSITIVE_HEADERS.to_vec(),
+            },
+            trusted_hosts: trusted_hosts
+                .unwrap_or_default()
+                .into_iter()
+                .map(|host| host.to_ascii_lowercase())
+                .collect(),
+        })
+    }
+
+    pub(crate) fn mark(&self, marker: &Marker) {
+        if let Some(callback) = self.on_redirect {
+            marker.mark(callback);
+        }
+    }
+
+    /// The redirect `response` asks for, if it is one and redirects are
+    /// followed. Fails for a `Location` that isn't a URL, and for 301 and 302
+    /// redirects of unsafe methods in strict mode.
+    pub(crate) fn next(
+        &self,
+        response: &RquestResponse,
+        method: &Method,
+    ) -> Result<Option<Redirect>, String> {
+        let status = response.status().as_u16();
+        if !self.follow || !matches!(status, 301 | 302 | 303 | 307 | 308) {
+            return Ok(None);
+        }
+        let Some(location) = response.headers().get(LOCATION) else {
+            return Ok(None);
+        };
+
+        let location = location
+            .to_str()
+            .ok()
+            .and_then(|location| response.url().join(location).ok())
+            .ok_or_else(|| format!("invalid redirect location: {:?}", location))?;
+
+        let safe = matches!(*method, Method::GET | Method::HEAD);
+        let method = match status {
+            303 if *method != Method::HEAD => Method::GET,
+            301 | 302 if !safe && self.strict => {
+                return Err(format!(
+                    "can't follow {} redirect for {} in strict mode",
+                    status, method
+                ));
+            }
+            301 | 302 if !safe => Method::GET,
+            _ => method.clone(),
+        };
+
+        Ok(Some(Redirect {
+            hop: Hop {
+                status,
+                url: response.url().clone(),
+                location,
+                headers: response.headers().clone(),
+            },
+            method,
+            keep_body: matches!(status, 307 | 308),
+        }))
+    }
+
+    /// Removes the sensitive headers from a request redirected from `from` to
+    /// `to`, if that changes the scheme, host or port and `to` isn't trusted.
+    pub(crate) fn strip(&self, from: &Url, to: &Url, headers: &mut HeaderMap) {
+        if from.origin() == to.origin() {
+            return;
+        }
+        let trusted = to
+            .host_str()
+            .is_some_and(|host| self.trusted_hosts.iter().any(|trusted| trusted == host));
+        if trusted {
+            return;
+        }
+
+        for name in &self.sensitive_headers {
+            headers.remove(name);
+        }
+    }
+
+    /// Whether there is an `on_redirect` callback, which needs the GVL.
+    pub(crate) fn has_callback(&self) -> bool {
+        self.on_redirect.is_some()
+    }
+
+    /// Calls the `on_redirect` callback, if any, with `(from, to, status)`.
+    /// Needs the GVL.
+    pub(crate) fn notify(&self, hop: &Hop) -> Result<(), MagnusError> {
+        let Some(callback) = self.on_redirect else {
+            return Ok(());
+        };
+
+        let ruby = Ruby::get().expect("redirect callbacks run on a Ruby thread");
+        let _: Value = ruby.get_inner(callback).funcall(
+            "call",
+            (hop.url.as_str(), hop.location.as_str(), hop.status),
+        )?;
+        Ok(())
+    }
+}
+
+/// The redirects that led to a response, as an Array of Hashes with `:status`,
+/// `:url`, `:location` and `:headers`.
+pub(crate) fn history(hops: &[Hop]) -> Result<RArray, MagnusError> {
+    let history = RArray::with_capacity(hops.len());
+    for hop in hops {
+        let entry = RHash::new();
+        entry.aset(Symbol::new("status"), hop.status)?;
+        entry.aset(Symbol::new("url"), hop.url.as_str())?;
+        entry.aset(Symbol::new("location"), hop.location.as_str())?;
+        entry.aset(
+            Symbol::new("headers"),
+            RbHeaders::new(hop.headers.clone()).into_value(),
+        )?;
+        history.push(entry)?;
+    }
+    Ok(history)
+}
@@ -0,0 +1,179 @@
+use crate::gvl;
+use bytes::Bytes;
+use futures_util::{future, stream};
+use magnus::value::ReprValue;
+use magnus::{Error as MagnusError, RString, Ruby, TryConvert, Value, exception};
+use rquest::{Body, RequestBuilder};
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::pin::pin;
+use tokio::sync::mpsc;
+
+/// How much is read from an IO at a time.
+pub(crate) const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How many chunks may be read ahead of the connection, which bounds the
+/// memory an upload uses.
+const READ_AHEAD: usize = 2;
+
+enum Source {
+    /// Anything with `read(length)`, such as a File or StringIO.
+    Io(Value),
+    /// An Enumerator yielding Strings.
+    Enumerator(Value),
+}
+
+impl Source {
+    /// Reads the next chunk, or `None` at the end. Needs the GVL.
+    fn read(&self) -> Result<Option<Bytes>, MagnusError> {
+        let chunk = match self {
+            Source::Io(io) => io.funcall::<_, _, Option<RString>>("read", (CHUNK_SIZE,))?,
+            Source::Enumerator(enumerator) => match enumerator.funcall::<_, _, Value>("next", ()) {
+                Ok(chunk) => Some(RString::try_convert(chunk)?),
+                Err(e) if e.is_kind_of(exception::stop_iteration()) => None,
+                Err(e) => return Err(e),
+            },
+        };
+
+        // SAFETY: the bytes are copied before the string can be touched again.
+        Ok(chunk.map(|chunk| Bytes::copy_from_slice(unsafe { chunk.as_slice() })))
+    }
+}
+
+/// A request body streamed from a Ruby IO or Enumerator.
+pub(crate) struct Upload {
+    source: Source,
+    /// The bytes left in the IO, when it can tell.
+    size: Option<u64>,
+}
+
+impl Upload {
+    /// Recognises an IO or Enumerator body, returning `None` for anything else.
+    pub(crate) fn from_value(value: Value) -> Result<Option<Self>, MagnusError> {
+        let ruby = Ruby::get().expect("bodies are read on a Ruby thread");
+
+        if value.is_kind_of(ruby.class_enumerator()) {
+            Ok(Some(Self {
+                source: Source::Enumerator(value),
+                size: None,
+            }))
+        } else if value.respond_to("read", false)? {
+            Ok(Some(Self {
+                source: Source::Io(value),
+                size: remaining(value)?,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Sets the body of `req` to a stream fed by the returned [`Feeder`], with a
+    /// `Content-Length` when the size is known and chunked encoding otherwise.
+    pub(crate) fn attach(self, req: RequestBuilder) -> (RequestBuilder, Feeder) {
+        let (body, size, feeder) = self.into_body();
+
+        let mut req = req.body(body);
+        if let Some(size) = size {
+            req = req.header("Content-Length", size);
+        }
+
+        (req, feeder)
+    }
+
+    /// Splits the upload into a body stream, its size if known, and the
+    /// [`Feeder`] that fills the stream.
+    pub(crate) fn into_body(self) -> (Body, Option<u64>, Feeder) {
+        let (tx, rx) = mpsc::channel(READ_AHEAD);
+        let body = Body::wrap_stream(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|chunk| (chunk, rx))
+        }));
+
+        let feeder = Feeder {
+            source: self.source,
+            tx,
+        };
+        (body, self.size, feeder)
+    }
+
+    /// The IO's file name, if it has a `path`.
+    pub(crate) fn filename(&self) -> Result<Option<String>, MagnusError> {
+        let Source::Io(io) = &self.source else {
+            return Ok(None);
+        };
+        if !io.respond_to("path", false)? {
+            return Ok(None);
+        }
+
+        let path = io.funcall::<_, _, Option<String>>("path", ())?;
+        Ok(path.as_deref().and_then(basename))
+    }
+}
+
+/// The bytes left to read in an IO that knows its size and position, like a
+/// File or StringIO. Pipes and sockets report neither.
+fn remaining(io: Value) -> Result<Option<u64>, MagnusError> {
+    if !io.respond_to("size", false)? || !io.respond_to("pos", false)? {
+        return Ok(None);
+    }
+
+    let size = io.funcall::<_, _, Option<u64>>("size", ())?;
+    let pos = io.funcall::<_, _, u64>("pos", ())?;
+    Ok(size.map(|size| size.saturating_sub(pos)))
+}
+
+/// Reads an upload from Ruby and hands it to the connection, a chunk at a time.
+pub(crate) struct Feeder {
+    source: Source,
+    tx: mpsc::Sender<Result<Bytes, io::Error>>,
+}
+
+impl Feeder {
+    /// Feeds the body until the source is exhausted or the connection stops
+    /// reading it. Must run on the Ruby thread, inside [`gvl::block_on`].
+    async fn run(self) -> Result<(), MagnusError> {
+        // Waiting for room first means nothing is read from Ruby that the
+        // connection won't take.
+        while let Ok(permit) = self.tx.reserve().await {
+            match gvl::with_gvl(|| self.source.read()) {
+                Ok(Some(chunk)) => permit.send(Ok(chunk)),
+                Ok(None) => break,
+                Err(e) => {
+                    // Fail the body, rather than closing it, so the server
+                    // doesn't see a truncated upload as complete.
+                    permit.send(Err(io::Error::other("failed to read the request body")));
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The last component of a path.
+pub(crate) fn basename(path: &str) -> Option<String> {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+}
+
+/// Drives `request` while feeding it its uploads. A Ruby error raised while
+/// reading an upload cancels the request.
+pub(crate) async fn feeding<F: Future>(
+    request: F,
+    feeders: Vec<Feeder>,
+) -> Result<F::Output, MagnusError> {
+    if feeders.is_empty() {
+        return Ok(request.await);
+    }
+
+    let mut request = pin!(request);
+    tokio::select! {
+        output = &mut request => Ok(output),
+        fed = future::try_join_all(feeders.into_iter().map(Feeder::run)) => {
+            fed?;
+            Ok(request.await)
+        }
+    }
+}
@@ -1,6 +1,9 @@
 require 'minitest/autorun'
 require_relative '../lib/rquest_rb'
 require 'json'
+require 'timeout'
+require 'stringio'
+require 'tempfile'
 
 class RquestTest < Minitest::Test
   HTTP = Rquest::HTTP
@@ -9,7 +12,7 @@ class RquestTest < Minitest::Test
     response = HTTP.get("https://tls.peet.ws/api/all")
     assert_equal(200, response.status)
     assert_kind_of(String, response.body)
-    assert_kind_of(Hash, response.headers)
+    assert_kind_of(HTTP::Headers, response.headers)
     
     # Verify the response contains TLS data
     data = JSON.parse(response.body)
@@ -112,6 +115,214 @@ class RquestTest < Minitest::Test
     assert is_mobile, "Expected a mobile user agent, but got: #{user_agent}"
   end
 
+  def test_explicit_emulation
+    response = HTTP.emulation(:firefox135).get("https://tls.peet.ws/api/all")
+    assert_equal(200, response.status)
+
+    body = JSON.parse(response.body)
+    headers = get_headers_from_response(body)
+    user_agent = headers.find { |h| h.start_with?("user-agent:") }
+    user_agent = user_agent ? user_agent.sub("user-agent: ", "") : body["user_agent"]
+
+    assert_match(/Firefox\/135/, user_agent)
+  end
+
+  def test_client_new_with_emulation
+    client = HTTP::Client.new(emulation: :safari_ios_17_4_1)
+    response = client.get("https://tls.peet.ws/api/all")
+    assert_equal(200, response.status)
+    assert_match(/iPhone/, JSON.parse(response.body)["user_agent"])
+  end
+
+  def test_emulation_kept_across_builder_calls
+    client = HTTP
+      .emulation(:firefox135)
+      .headers(accept: "*/*")
+      .follow(true)
+      .timeout(30)
+
+    response = client.get("https://tls.peet.ws/api/all")
+    assert_equal(200, response.status)
+    assert_match(/Firefox\/135/, JSON.parse(response.body)["user_agent"])
+  end
+
+  def test_chained_client_keeps_fingerprint
+    client = HTTP.desktop
+    first = JSON.parse(client.get("https://tls.peet.ws/api/all").body)
+    second = JSON.parse(client.headers(accept: "*/*").get("https://tls.peet.ws/api/all").body)
+
+    assert_equal(first["tls"]["ja4"], second["tls"]["ja4"])
+    assert_equal(first["user_agent"], second["user_agent"])
+  end
+
+  def test_module_functions_share_default_client
+    first = JSON.parse(HTTP.get("https://tls.peet.ws/api/all").body)
+    second = JSON.parse(HTTP.headers(accept: "*/*").get("https://tls.peet.ws/api/all").body)
+
+    assert_equal(first["user_agent"], second["user_agent"])
+  end
+
+  def test_derived_clients_work_across_threads
+    client = HTTP::Client.new
+    threads = 4.times.map do |i|
+      Thread.new { client.headers("X-Thread" => i.to_s).get("https://httpbin.org/get").status }
+    end
+
+    assert_equal([200] * 4, threads.map(&:value))
+  end
+
+  def test_requests_release_the_gvl
+    started = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+    threads = 5.times.map do
+      Thread.new { HTTP.get("https://httpbin.org/delay/2").status }
+    end
+
+    assert_equal([200] * 5, threads.map(&:value))
+    elapsed = Process.clock_gettime(Process::CLOCK_MONOTONIC) - started
+    assert_operator(elapsed, :<, 8, "requests ran one at a time (#{elapsed}s)")
+  end
+
+  def test_timeout_interrupts_in_flight_request
+    started = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+    assert_raises(Timeout::Error) do
+      Timeout.timeout(1) { HTTP.get("https://httpbin.org/delay/10") }
+    end
+
+    elapsed = Process.clock_gettime(Process::CLOCK_MONOTONIC) - started
+    assert_operator(elapsed, :<, 3)
+  end
+
+  def test_thread_kill_cancels_request
+    thread = Thread.new { HTTP.get("https://httpbin.org/delay/10") }
+    sleep 0.5
+    thread.kill
+
+    assert(thread.join(2), "killed thread did not stop")
+  end
+
+  def test_parallel_requests
+    started = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+    results = HTTP.parallel([
+      [:get, "https://httpbin.org/delay/2"],
+      [:post, "https://httpbin.org/post", { body: "batched" }],
+      ["PUT", "https://httpbin.org/put", { body: "updated" }],
+      [:get, "https://httpbin.org/status/404"]
+    ], concurrency: 4)
+    elapsed = Process.clock_gettime(Process::CLOCK_MONOTONIC) - started
+
+    assert_equal([200, 200, 200, 404], results.map(&:status))
+    assert_equal("batched", JSON.parse(results[1].body)["data"])
+    assert_operator(elapsed, :<, 6)
+  end
+
+  def test_parallel_slow_entry_does_not_hold_back_the_rest
+    started = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+    requests = [[:get, "https://httpbin.org/delay/8"]]
+    requests += [[:get, "https://httpbin.org/delay/2"]] * 6
+    results = HTTP.parallel(requests, concurrency: 3)
+    elapsed = Process.clock_gettime(Process::CLOCK_MONOTONIC) - started
+
+    assert_equal([200] * 7, results.map(&:status))
+    assert_equal("https://httpbin.org/delay/8", results[0].uri)
+    # The fast entries share the two free slots while the slow one runs, so
+    # the batch takes about 8s. Held slots would push it to about 12s.
+    assert_operator(elapsed, :<, 10.5)
+  end
+
+    results = HTTP.parallel([
+      [:get, "http://127.0.0.1:1/"],
+      [:get, "https://httpbin.org/get"]
+    ])
+
+    assert_kind_of(HTTP::ConnectionError, results[0])
+    assert_equal("http://127.0.0.1:1/", results[0].url)
+    assert_equal(200, results[1].status)
+  end
+
+  def test_parallel_returns_body_errors_per_entry
+    chunks = Enumerator.new do |y|
+      y << "first,"
+      raise ArgumentError, "source failed"
+    end
+
+    results = HTTP.parallel([
+      [:post, "https://httpbin.org/post", { body: chunks }],
+      [:get, "https://httpbin.org/get"]
+    ])
+
+    assert_kind_of(ArgumentError, results[0])
+    assert_equal("source failed", results[0].message)
+    assert_equal(200, results[1].status)
+  end
+
+  def test_each_completed_yields_as_requests_finish
+    order = []
+    HTTP.each_completed([
+      [:get, "https://httpbin.org/delay/3"],
+      [:get, "https://httpbin.org/get"]
+    ], concurrency: 2) do |index, response|
+      assert_equal(200, response.status)
+      order << index
+    end
+
+    assert_equal([1, 0], order)
+  end
+
+  def test_each_completed_yields_errors
+    yielded = {}
+    HTTP.each_completed([[:get, "http://127.0.0.1:1/"]]) do |index, result|
+      yielded[index] = result
+    end
+
+    assert_kind_of(HTTP::ConnectionError, yielded[0])
+  end
+
+  def test_each_completed_break_cancels_remaining
+    started = Process.clock_gettime(Process::CLOCK_MONOTONIC)
+    result = HTTP.each_completed([
+      [:get, "https://httpbin.org/get"],
+      [:get, "https://httpbin.org/delay/10"]
+    ]) do |index, response|
+      break response.status
+    end
+    elapsed = Process.clock_gettime(Process::CLOCK_MONOTONIC) - started
+
+    assert_equal(200, result)
+    assert_operator(elapsed, :<, 8)
+  end
+
+  def test_each_completed_allows_requests_from_the_block
+    statuses = []
+    HTTP.each_completed([[:get, "https://httpbin.org/get"]]) do |_index, response|
+      statuses << response.status
+      statuses << HTTP.get("https://httpbin.org/status/204").status
+    end
+
+    assert_equal([200, 204], statuses)
+  end
+
+  def test_each_completed_requires_a_block
+    assert_raises(LocalJumpError) { HTTP.each_completed([[:get, "https://httpbin.org/get"]]) }
+  end
+
+  def test_parallel_validates_arguments
+    assert_raises(ArgumentError) { HTTP.parallel([[:get]]) }
+    assert_raises(ArgumentError) { HTTP.parallel([[:get, "https://httpbin.org/get"]], concurrency: 0) }
+    assert_raises(ArgumentError) { HTTP.parallel([["NOT A VERB", "https://httpbin.org/get"]]) }
+  end
+
+  def test_emulations
+    names = HTTP.emulations
+    assert_includes(names, :chrome134)
+    assert_includes(names, :safari_ios_17_4_1)
+    names.each { |name| assert_kind_of(HTTP::Client, HTTP.emulation(name)) }
+  end
+
+  def test_unknown_emulation
+    assert_raises(ArgumentError) { HTTP.emulation(:netscape4) }
+    assert_raises(ArgumentError) { HTTP::Client.new(emulation: "netscape4") }
+  end
+
   def test_headers
     response = HTTP
       .headers(accept: "application/json", user_agent: "Test Client")
@@ -128,6 +339,203 @@ class RquestTest < Minitest::Test
     assert_match(/application\/json/, accept_header || "") if accept_header
   end
 
+  def test_get_params
+    response = HTTP.get(
+      "https://httpbin.org/get?page=1",
+      params: { q: "a b&c", tags: ["x", "y"] }
+    )
+
+    assert_equal(200, response.status)
+    args = JSON.parse(response.body)["args"]
+    assert_equal({ "page" => "1", "q" => "a b&c", "tags" => ["x", "y"] }, args)
+  end
+
+  def test_params_styles
+    brackets = HTTP.params_style(:brackets).get("https://httpbin.org/get", params: { tags: ["x", "y"] })
+    assert_equal(["x", "y"], JSON.parse(brackets.body)["args"]["tags[]"])
+
+    comma = HTTP.params_style(:comma).delete("https://httpbin.org/delete", params: { tags: ["x", "y"] })
+    assert_equal("x,y", JSON.parse(comma.body)["args"]["tags"])
+
+    assert_raises(ArgumentError) { HTTP.params_style(:semicolon) }
+  end
+
+  def test_per_request_options
+    response = HTTP.get(
+      "https://httpbin.org/get",
+      headers: { "X-Request-Id" => "42" },
+      timeout: 10
+    )
+
+    assert_equal(200, response.status)
+    assert_equal("42", JSON.parse(response.body)["headers"]["X-Request-Id"])
+  end
+
+  def test_per_request_follow
+    response = HTTP.get("https://httpbin.org/redirect/1", follow: false)
+    assert_equal(302, response.status)
+  end
+
+  def test_unknown_option_raises
+    assert_raises(ArgumentError) { HTTP.get("https://httpbin.org/get", verify: false) }
+    assert_raises(ArgumentError) do
+      HTTP.post("https://httpbin.org/post", body: "a", json: { "a" => 1 })
+    end
+  end
+
+  def test_parallel_entry_options
+    responses = HTTP.parallel([
+      [:get, "https://httpbin.org/get", { params: { page: 2 }, headers: { "X-Entry" => "1" } }]
+    ])
+
+    data = JSON.parse(responses.first.body)
+    assert_equal({ "page" => "2" }, data["args"])
+    assert_equal("1", data["headers"]["X-Entry"])
+  end
+
+  def test_generic_request
+    response = HTTP.request(:put, "https://httpbin.org/put", json: { "a" => 1 })
+    assert_equal({ "a" => 1 }, JSON.parse(response.body)["json"])
+
+    options = HTTP.options("https://httpbin.org/get")
+    assert_equal(200, options.status)
+    assert_includes(options.headers["allow"] || options.headers["access-control-allow-methods"], "GET")
+
+    assert_raises(ArgumentError) { HTTP.request("BAD METHOD", "https://httpbin.org/get") }
+  end
+
+  def test_no_forced_json_defaults
+    sent = JSON.parse(HTTP.post("https://httpbin.org/post", body: "plain").body)["headers"]
+    refute_equal("application/json", sent["Accept"])
+    refute_equal("application/json", sent["Content-Type"])
+
+    sent = JSON.parse(HTTP.accept(:json).get("https://httpbin.org/headers").body)["headers"]
+    assert_equal("application/json", sent["Accept"])
+
+    assert_raises(ArgumentError) { HTTP.accept(:yaml) }
+  end
+
+  def test_symbol_header_keys_and_merging
+    client = HTTP
+      .headers(user_agent: "First/1.0", x_trace_id: "abc")
+      .headers("User-Agent" => "Second/2.0")
+    sent = JSON.parse(client.get("https://httpbin.org/headers").body)["headers"]
+
+    assert_equal("Second/2.0", sent["User-Agent"])
+    assert_equal("abc", sent["X-Trace-Id"])
+  end
+
+  def test_invalid_headers_raise
+    assert_raises(ArgumentError) { HTTP.headers("X-Test" => "a\r\nInjected: yes") }
+    assert_raises(ArgumentError) { HTTP.headers("Bad\nName" => "a") }
+    assert_raises(ArgumentError) do
+      HTTP.get("https://httpbin.org/get", headers: { "X-Test" => "a\nb" })
+    end
+  end
+
+  def test_cookie_jar_across_redirects
+    jar = HTTP::CookieJar.new
+    response = HTTP.cookies(jar).get("https://httpbin.org/cookies/set?session=abc")
+
+    assert_equal({ "session" => "abc" }, JSON.parse(response.body)["cookies"])
+    assert_equal({ "session" => "abc" }, jar.cookies_for("https://httpbin.org/cookies"))
+
+    other = HTTP.desktop.cookies(jar)
+    assert_equal({ "session" => "abc" }, JSON.parse(other.get("https://httpbin.org/cookies").body)["cookies"])
+  end
+
+  def test_cookie_jar_management
+    jar = HTTP::CookieJar.new
+    jar.add("https://example.com", "a=1; Path=/")
+    jar.add("https://example.com", "b=2; Path=/")
+    assert_equal({ "a" => "1", "b" => "2" }, jar.cookies_for("https://example.com/"))
+    assert_empty(jar.cookies_for("https://example.org/"))
+
+    assert(jar.delete("https://example.com", "a"))
+    refute(jar.delete("https://example.com", "a"))
+    assert_equal({ "b" => "2" }, jar.cookies_for("https://example.com/"))
+
+    jar.clear
+    assert_empty(jar.cookies_for("https://example.com/"))
+
+    client = HTTP.cookies
+    assert_kind_of(HTTP::CookieJar, client.cookie_jar)
+    assert_raises(ArgumentError) { jar.add("not a url", "a=1") }
+  end
+
+  def test_cookie_jar_save_and_load
+    jar = HTTP::CookieJar.new
+    jar.add("https://example.com", "session=abc; Path=/; Secure; HttpOnly; SameSite=Strict")
+    jar.add("https://example.com", "theme=dark; Domain=example.com; Path=/; Max-Age=3600")
+
+    Tempfile.create(["cookies", ".txt"]) do |file|
+      jar.save(file.path)
+      lines = File.readlines(file.path, chomp: true)
+      assert_equal("# Netscape HTTP Cookie File", lines.first)
+      assert_includes(lines, "#HttpOnly_example.com\tFALSE\t/\tTRUE\t0\tsession\tabc")
+      assert(lines.any? { |line| line.start_with?(".example.com\tTRUE\t/\tFALSE\t") })
+
+      loaded = HTTP::CookieJar.load(file.path)
+      assert_equal({ "session" => "abc", "theme" => "dark" }, loaded.cookies_for("https://example.com/"))
+      assert_equal({ "theme" => "dark" }, loaded.cookies_for("https://www.example.com/"))
+    end
+
+    Tempfile.create(["state", ".json"]) do |file|
+      jar.save(file.path, format: :json)
+      cookies = JSON.parse(File.read(file.path))["cookies"]
+      session = cookies.find { |cookie| cookie["name"] == "session" }
+      assert_equal(
+        { "domain" => "example.com", "expires" => -1, "httpOnly" => true, "secure" => true, "sameSite" => "Strict" },
+        session.slice("domain", "expires", "httpOnly", "secure", "sameSite")
+      )
+      assert_equal(".example.com", cookies.find { |cookie| cookie["name"] == "theme" }["domain"])
+
+      loaded = HTTP::CookieJar.load(file.path)
+      assert_equal({ "session" => "abc", "theme" => "dark" }, loaded.cookies_for("https://example.com/"))
+    end
+  end
+
+  def test_cookie_jar_load_skips_expired_cookies
+    Tempfile.create(["cookies", ".txt"]) do |file|
+      file.write("example.com\tFALSE\t/\tFALSE\t1\told\tx\n")
+      file.write("example.com\tFALSE\t/\tFALSE\t#{Time.now.to_i + 3600}\tfresh\ty\n")
+      file.flush
+
+      assert_equal({ "fresh" => "y" }, HTTP::CookieJar.load(file.path).cookies_for("https://example.com/"))
+    end
+
+    assert_raises(ArgumentError) { HTTP::CookieJar.new.save("cookies.txt", format: :yaml) }
+  end
+
+  def test_response_headers_keep_repeated_values
+    response = HTTP.get("https://httpbin.org/response-headers?X-Multi=a&X-Multi=b")
+
+    headers = response.headers
+    assert_equal(["a", "b"], headers.get("x-multi"))
+    assert_equal(["a", "b"], headers["X-MULTI"])
+    assert_match(%r{application/json}, headers["Content-Type"])
+    assert_equal(["a", "b"], headers.to_h["X-Multi"])
+    assert_includes(headers.to_a, ["X-Multi", "a"])
+    assert_includes(headers.keys, "Content-Type")
+    assert_nil(headers["X-Missing"])
+  end
+
+  def test_headers_object
+    headers = HTTP::Headers.new("Accept" => "text/html")
+    headers.add("Set-Cookie", "a=1")
+    headers.add(:"set-cookie", "b=2")
+    assert_equal(["a=1", "b=2"], headers.get("SET-COOKIE"))
+
+    headers["Accept"] = "application/json"
+    assert_equal("application/json", headers["accept"])
+    assert_equal([["Accept", "application/json"], ["Set-Cookie", "a=1"], ["Set-Cookie", "b=2"]], headers.map { |name, value| [name, value] })
+
+    headers.delete("Set-Cookie")
+    refute(headers.include?("set-cookie"))
+    assert_raises(ArgumentError) { headers.add("Bad Name", "x") }
+    assert_raises(ArgumentError) { headers.set("X-Test", "a\r\nb") }
+  end
+
   def test_post_request
     response = HTTP.post(
       "https://httpbin.org/post",
@@ -152,6 +560,152 @@ class RquestTest < Minitest::Test
     assert_equal({ "name" => "test", "value" => 123 }, JSON.parse(body["data"]))
   end
 
+  def test_post_json_option
+    response = HTTP.post(
+      "https://httpbin.org/post",
+      json: { name: "test", values: [1, 2.5, nil, true], nested: { ok: false } }
+    )
+
+    assert_equal(200, response.status)
+    body = JSON.parse(response.body)
+    assert_equal("application/json", body["headers"]["Content-Type"])
+    assert_equal(
+      { "name" => "test", "values" => [1, 2.5, nil, true], "nested" => { "ok" => false } },
+      body["json"]
+    )
+  end
+
+  def test_json_option_type_error
+    error = assert_raises(TypeError) do
+      HTTP.post("https://httpbin.org/post", json: { user: { tags: [1, Object.new] } })
+    end
+    assert_includes(error.message, "$.user.tags[1]")
+
+    assert_raises(ArgumentError) do
+      HTTP.post("https://httpbin.org/post", json: {}, body: "{}")
+    end
+  end
+
+  def test_json_option_nesting_limit
+    hash = {}
+    hash[:a] = hash
+    error = assert_raises(ArgumentError) do
+      HTTP.post("https://httpbin.org/post", json: hash)
+    end
+    assert_includes(error.message, "too deep")
+  end
+
+  def test_post_io
+    io = StringIO.new("x" * 200_000)
+    response = HTTP.post("https://httpbin.org/post", body: io)
+
+    assert_equal(200, response.status)
+    body = JSON.parse(response.body)
+    assert_equal("200000", body["headers"]["Content-Length"])
+    assert_equal(200_000, body["data"].bytesize)
+  end
+
+  def test_post_io_see_other_redirect
+    url = "https://httpbin.org/redirect-to?url=%2Fheaders&status_code=303"
+    response = HTTP.desktop.post(url, body: StringIO.new("data"), emulation: :safari_ios_17_4_1)
+
+    assert_equal(200, response.status)
+    assert_equal([303], response.history.map { |hop| hop[:status] })
+    sent = JSON.parse(response.body)["headers"]
+    assert_match(/iPhone/, sent["User-Agent"])
+    assert_nil(sent["Content-Length"])
+  end
+
+  def test_post_file
+    Tempfile.create("upload") do |file|
+      file.write("file contents")
+      file.rewind
+
+      response = HTTP.post("https://httpbin.org/post", body: file)
+      assert_equal(200, response.status)
+      assert_equal("file contents", JSON.parse(response.body)["data"])
+    end
+  end
+
+  def test_post_enumerator
+    chunks = Enumerator.new do |y|
+      y << "first,"
+      y << "second"
+    end
+
+    response = HTTP.post("https://httpbin.org/post", body: chunks)
+    assert_equal(200, response.status)
+  end
+
+  def test_post_enumerator_error
+    chunks = Enumerator.new do |y|
+      y << "first,"
+      raise ArgumentError, "source failed"
+    end
+
+    error = assert_raises(ArgumentError) do
+      HTTP.post("https://httpbin.org/post", body: chunks)
+    end
+    assert_equal("source failed", error.message)
+  end
+
+  def test_post_form
+    response = HTTP.post(
+      "https://httpbin.org/post",
+      form: { name: "test", user: { tags: ["a", "b"] }, empty: nil }
+    )
+
+    assert_equal(200, response.status)
+    body = JSON.parse(response.body)
+    assert_includes(body["headers"]["Content-Type"], "application/x-www-form-urlencoded")
+    assert_equal("test", body["form"]["name"])
+    assert_equal(["a", "b"], body["form"]["user[tags][]"])
+    assert_equal("", body["form"]["empty"])
+  end
+
+  def test_form_nesting_limit
+    list = []
+    list << list
+    error = assert_raises(ArgumentError) do
+      HTTP.post("https://httpbin.org/post", form: { list: list })
+    end
+    assert_includes(error.message, "too deep (at list[][]")
+  end
+
+  def test_post_multipart_form
+    Tempfile.create(["upload", ".txt"]) do |file|
+      file.write("file contents")
+      file.flush
+
+      response = HTTP.post(
+        "https://httpbin.org/post",
+        form: {
+          name: "test",
+          file: HTTP::FormData::File.new(file.path, content_type: "text/plain"),
+          notes: HTTP::FormData::File.new(StringIO.new("some notes"), filename: "notes.txt")
+        }
+      )
+
+      assert_equal(200, response.status)
+      body = JSON.parse(response.body)
+      assert_includes(body["headers"]["Content-Type"], "multipart/form-data")
+      assert_equal("test", body["form"]["name"])
+      assert_equal("file contents", body["files"]["file"])
+      assert_equal("some notes", body["files"]["notes"])
+    end
+  end
+
+  def test_form_file_options
+    file = HTTP::FormData::File.new(StringIO.new("x"), filename: "x.bin", content_type: "application/x-test")
+    assert_equal("x.bin", file.filename)
+    assert_equal("application/x-test", file.content_type)
+
+    assert_raises(TypeError) { HTTP::FormData::File.new(42) }
+    assert_raises(ArgumentError) do
+      HTTP.post("https://httpbin.org/post", body: "a", form: { a: 1 })
+    end
+  end
+
   def test_put_request
     response = HTTP.put(
       "https://httpbin.org/put",
@@ -203,18 +757,185 @@ class RquestTest < Minitest::Test
     assert_equal("https://httpbin.org/redirect/1", response.uri)
   end
 
+  def test_redirect_history
+    response = HTTP.get("https://httpbin.org/redirect/3")
+
+    assert_equal(200, response.status)
+    assert_equal([302, 302, 302], response.history.map { |hop| hop[:status] })
+    assert_equal("https://httpbin.org/redirect/3", response.history.first[:url])
+    assert_equal("https://httpbin.org/relative-redirect/2", response.history.first[:location])
+    assert_kind_of(HTTP::Headers, response.history.first[:headers])
+    assert_equal([], HTTP.get("https://httpbin.org/get").history)
+  end
+
+  def test_too_many_redirects
+    error = assert_raises(HTTP::TooManyRedirectsError) do
+      HTTP.follow(max_hops: 2).get("https://httpbin.org/redirect/5")
+    end
+
+    assert_kind_of(HTTP::RedirectError, error)
+    assert_equal(3, error.history.size)
+    assert_equal("https://httpbin.org/redirect/5", error.url)
+  end
+
+  def test_on_redirect_callback
+    hops = []
+    HTTP
+      .follow(on_redirect: ->(from, to, status) { hops << [from, to, status] })
+      .get("https://httpbin.org/redirect/2")
+
+    assert_equal(
+      [
+        ["https://httpbin.org/redirect/2", "https://httpbin.org/relative-redirect/1", 302],
+        ["https://httpbin.org/relative-redirect/1", "https://httpbin.org/get", 302]
+      ],
+      hops
+    )
+    assert_raises(TypeError) { HTTP.follow(on_redirect: "nope") }
+  end
+
+  def test_strict_redirects
+    url = "https://httpbin.org/redirect-to?url=%2Fget&status_code=302"
+
+    assert_equal(200, HTTP.follow(strict: false).post(url, body: "data").status)
+    assert_raises(HTTP::RedirectError) do
+      HTTP.follow(strict: true).post(url, body: "data")
+    end
+  end
+
+  def test_cross_origin_redirect_strips_credentials
+    client = HTTP.headers(authorization: "Bearer token", x_api_key: "secret")
+    same = "https://httpbin.org/redirect-to?url=%2Fheaders"
+    other = "https://httpbin.org/redirect-to?url=http%3A%2F%2Fhttpbin.org%2Fheaders"
+
+    sent = JSON.parse(client.get(same).body)["headers"]
+    assert_equal("Bearer token", sent["Authorization"])
+
+    sent = JSON.parse(client.get(other).body)["headers"]
+    assert_nil(sent["Authorization"])
+    assert_equal("secret", sent["X-Api-Key"])
+
+    sent = JSON.parse(client.follow(sensitive_headers: [:x_api_key]).get(other).body)["headers"]
+    assert_equal("Bearer token", sent["Authorization"])
+    assert_nil(sent["X-Api-Key"])
+
+    sent = JSON.parse(client.follow(trusted_hosts: ["httpbin.org"]).get(other).body)["headers"]
+    assert_equal("Bearer token", sent["Authorization"])
+  end
+
+  def test_per_request_redirect_options
+    assert_raises(HTTP::TooManyRedirectsError) do
+      HTTP.get("https://httpbin.org/redirect/2", follow: { max_hops: 1 })
+    end
+  end
+
   def test_response_methods
     response = HTTP.get("https://tls.peet.ws/api/all")
     
     assert_kind_of(Integer, response.status)
     assert_kind_of(String, response.body)
-    assert_kind_of(Hash, response.headers)
+    assert_kind_of(Rquest::HTTP::Headers, response.headers)
+    assert_equal(response.headers["Content-Type"], response.headers["content-type"])
+    refute_nil(response.headers["CONTENT-TYPE"])
     assert_kind_of(String, response.uri)
     assert_kind_of(String, response.to_s)
     assert_kind_of(Integer, response.code)
     assert_equal(response.status, response.code)
   end
 
+  def test_binary_body
+    response = HTTP.get("https://httpbin.org/image/png")
+    assert_equal(200, response.status)
+
+    bytes = response.bytes
+    assert_equal(Encoding::ASCII_8BIT, bytes.encoding)
+    assert_equal("\x89PNG\r\n\x1A\n".b, bytes[0, 8])
+    assert_equal(bytes, response.body.to_s)
+    assert_equal(response.headers["content-length"].to_i, bytes.bytesize)
+  end
+
+  def test_streaming_body
+    response = HTTP.stream.get("https://httpbin.org/stream-bytes/65536?chunk_size=1024")
+    assert_equal(200, response.status)
+
+    body = response.body
+    assert_kind_of(HTTP::Response::Body, body)
+
+    first = body.readpartial(100)
+    assert_equal(Encoding::ASCII_8BIT, first.encoding)
+    assert_operator(first.bytesize, :<=, 100)
+
+    total = first.bytesize
+    body.each { |chunk| total += chunk.bytesize }
+    assert_equal(65536, total)
+
+    assert_raises(EOFError) { body.readpartial }
+    assert_raises(IOError) { body.to_s }
+  end
+
+  def test_streaming_body_to_s
+    response = HTTP.stream.get("https://httpbin.org/get")
+    text = response.body.to_s
+
+    assert_equal(Encoding::UTF_8, text.encoding)
+    assert_includes(text, "httpbin.org")
+    assert_equal(text, response.to_s)
+  end
+
+  def test_text_body
+    response = HTTP.get("https://httpbin.org/encoding/utf8")
+    text = response.to_s
+
+    assert_equal(Encoding::UTF_8, text.encoding)
+    assert(text.valid_encoding?)
+    assert_includes(text, "∮ E⋅da = Q")
+  end
+
+  def test_declared_charset
+    response = HTTP.get("https://httpbin.org/response-headers?Content-Type=text/plain;%20charset=Shift_JIS")
+
+    assert_equal("Shift_JIS", response.charset)
+    assert_equal(Encoding::Shift_JIS, response.body.encoding)
+    assert_equal(Encoding::ASCII_8BIT, response.bytes.encoding)
+
+    text = response.to_s(encoding: "UTF-8")
+    assert_equal(Encoding::UTF_8, text.encoding)
+    assert(text.valid_encoding?)
+  end
+
+  def test_parse_json
+    response = HTTP.get("https://httpbin.org/json")
+
+    parsed = response.parse
+    assert_kind_of(Hash, parsed)
+    assert_kind_of(String, parsed["slideshow"]["title"])
+
+    symbolized = response.parse(symbolize_keys: true, freeze: true)
+    assert_kind_of(Array, symbolized[:slideshow][:slides])
+    assert(symbolized.frozen?)
+    assert(symbolized[:slideshow][:title].frozen?)
+  end
+
+  def test_parse_forced_type_and_errors
+    response = HTTP.get("https://httpbin.org/html")
+
+    error = assert_raises(HTTP::ParseError) { response.parse }
+    assert_includes(error.message, "text/html")
+    assert_equal("https://httpbin.org/html", error.url)
+
+    assert_raises(HTTP::ParseError) { response.parse("application/json") }
+  end
+
+  def test_register_decoder
+    HTTP.register_decoder("text/x-test-lines") { |body| body.lines.map(&:chomp) }
+
+    response = HTTP.get("https://httpbin.org/response-headers?Content-Type=text/x-test-lines")
+    assert_kind_of(Array, response.parse)
+    assert_equal(response.to_s.lines.map(&:chomp), response.parse)
+
+    assert_raises(ArgumentError) { HTTP.register_decoder("text/plain") }
+  end
+
   def test_content_type_and_charset
     response = HTTP
       .headers(accept: "application/json")
@@ -233,6 +954,69 @@ class RquestTest < Minitest::Test
     end
   end
 
+  def test_connection_error
+    error = assert_raises(HTTP::ConnectionError) do
+      HTTP.get("http://127.0.0.1:1/")
+    end
+
+    assert_kind_of(HTTP::Error, error)
+    assert_equal("GET", error.request_method)
+    assert_equal("http://127.0.0.1:1/", error.url)
+    refute_nil(error.reason)
+  end
+
+  def test_error_hierarchy
+    [
+      HTTP::TimeoutError,
+      HTTP::ConnectionError,
+      HTTP::RedirectError,
+      HTTP::BodyDecodeError,
+      HTTP::RequestError
+    ].each do |klass|
+      assert_operator(klass, :<, HTTP::Error)
+    end
+
+    assert_operator(HTTP::ProxyError, :<, HTTP::ConnectionError)
+    assert_operator(HTTP::TLSError, :<, HTTP::ConnectionError)
+    assert_operator(HTTP::TooManyRedirectsError, :<, HTTP::RedirectError)
+    assert_operator(HTTP::Error, :<, StandardError)
+  end
+
+  def test_tls_error
+    assert_raises(HTTP::TLSError) do
+      HTTP.get("https://expired.badssl.com/")
+    end
+  end
+
+  def test_global_timeout
+    error = assert_raises(HTTP::TimeoutError) do
+      HTTP.timeout(1).get("https://httpbin.org/delay/5")
+    end
+
+    assert_equal("https://httpbin.org/delay/5", error.url)
+  end
+
+  def test_read_timeout
+    assert_raises(HTTP::ReadTimeoutError) do
+      HTTP.timeout(connect: 5, read: 1).get("https://httpbin.org/delay/5")
+    end
+  end
+
+  def test_connect_timeout
+    assert_raises(HTTP::ConnectTimeoutError) do
+      HTTP.timeout(connect: 1).get("http://10.255.255.1/")
+    end
+  end
+
+  def test_timeout_keeps_request_working
+    response = HTTP.timeout(connect: 5, read: 10, write: 10).get("https://httpbin.org/get")
+    assert_equal(200, response.status)
+  end
+
+  def test_timeout_rejects_unknown_options
+    assert_raises(ArgumentError) { HTTP.timeout(keep_alive: 5) }
+  end
+
   def test_bing_search_results
     # Create a client with a common browser user agent to avoid being blocked
     client = HTTP::Client.new